@@ -4,17 +4,41 @@ use std::io;
 use std::path::Path;
 use walkdir::WalkDir;
 #[path = "data.rs"]
+#[allow(dead_code)]
 mod data;
 
 #[path = "diff.rs"]
+#[allow(dead_code)]
 mod diff;
 
+#[path = "sign.rs"]
+#[allow(dead_code)]
+mod sign;
+
 pub struct Commit {
     pub tree: String,
     pub parents: Vec<String>,
     pub message: String,
 }
 
+pub struct Tag {
+    pub object: String,
+    pub object_type: String,
+    pub name: String,
+    pub tagger: String,
+    pub message: String,
+}
+
+pub struct MergeOptions {
+    // "recursive", "resolve" or "ours"
+    pub strategy: String,
+    // Side that wins the conflicting hunks ("ours" or "theirs"), if any
+    pub favor: String,
+    pub no_ff: bool,
+    pub ff_only: bool,
+    pub squash: bool,
+}
+
 pub fn write_tree(directory: String) -> String {
     let mut entries: Vec<(String, String, String)> = vec![];
     let mut name;
@@ -55,11 +79,45 @@ pub fn write_tree(directory: String) -> String {
         tree.push_str(&format!("{} {} {}\n", entry.2, entry.1, entry.0));
     }
 
-    return data::hash_object(&tree.into_bytes(), "tree".to_owned());
+    data::hash_object(&tree.into_bytes(), "tree".to_owned())
+}
+
+pub fn write_tree_from(files: &HashMap<String, String>) -> String {
+    // Same as write_tree, but the tree is built from a path -> oid map (such
+    // as the index) instead of the files in the working directory.
+    let mut entries: Vec<(String, String, String)> = vec![];
+    let mut subdirs: HashMap<String, HashMap<String, String>> = HashMap::new();
+
+    for (path, oid) in files {
+        let parts: Vec<&str> = path.splitn(2, "/").collect();
+        if parts.len() == 1 {
+            entries.push((parts[0].to_owned(), oid.clone(), "blob".to_owned()));
+        } else {
+            subdirs
+                .entry(parts[0].to_owned())
+                .or_default()
+                .insert(parts[1].to_owned(), oid.clone());
+        }
+    }
+
+    for (name, subdir) in subdirs.iter() {
+        entries.push((name.clone(), write_tree_from(subdir), "tree".to_owned()));
+    }
+
+    entries.sort();
+
+    let mut tree = String::new();
+    for entry in entries {
+        tree.push_str(&format!("{} {} {}\n", entry.2, entry.1, entry.0));
+    }
+
+    data::hash_object(&tree.into_bytes(), "tree".to_owned())
 }
 
 pub fn read_tree(oid: String) {
     empty_current_directory(".").unwrap();
+    data::set_index(get_tree(oid.clone(), "".to_owned()));
+    data::set_index_conflicts(HashMap::new());
     for (path, object_id) in get_tree(oid, "./".to_owned()).iter() {
         let mut dirs = Path::new(path).ancestors();
         dirs.next();
@@ -72,22 +130,50 @@ pub fn read_tree(oid: String) {
     }
 }
 
-pub fn commit(message: &str) -> String {
-    let mut commit = format!("tree {}\n", write_tree(".".to_owned()));
+pub fn commit(message: &str, signed: bool) -> String {
+    let mut message = message.to_owned();
+    if message.is_empty() {
+        message = data::read_rgit_file("MERGE_MSG");
+    }
+    if message.is_empty() {
+        message = data::read_rgit_file("SQUASH_MSG");
+    }
+
+    let conflicts = data::get_index_conflicts();
+    if !conflicts.is_empty() {
+        let mut paths: Vec<&String> = conflicts.keys().collect();
+        paths.sort();
+        panic!(
+            "Cannot commit, fix the conflicts and run add on them first: {:?}",
+            paths
+        );
+    }
+
+    let mut commit = format!("tree {}\n", write_tree_from(&data::get_index()));
 
+    let mut reason = "commit (initial)";
     let head = data::get_ref("HEAD".to_owned(), true);
-    if head.value != "" {
+    if !head.value.is_empty() {
         commit += format!("parent {}\n", head.value).as_str();
+        reason = "commit";
     }
     let merge_head = data::get_ref("MERGE_HEAD".to_owned(), true);
-    if merge_head.value != "" {
-        commit += format!("parent {}\n", merge_head.value).as_str();
-        data::delete_ref("MERGE_HEAD".to_owned(), false);
+    if !merge_head.value.is_empty() {
+        reason = "commit (merge)";
+        for parent in merge_head.value.lines() {
+            commit += format!("parent {}\n", parent).as_str();
+        }
+        clear_merge_state();
     }
 
     commit += "\n";
     commit += format!("{}\n", message).as_str();
+    if signed {
+        commit = sign::add_signature(&commit);
+    }
 
+    let subject = message.lines().next().unwrap_or("");
+    let reason = format!("{}: {}", reason, subject);
     let oid = data::hash_object(&commit.into_bytes(), "commit".to_owned());
     data::update_ref(
         "HEAD".to_owned(),
@@ -96,43 +182,49 @@ pub fn commit(message: &str) -> String {
             symbolic: false,
         },
         true,
+        &reason,
     );
-    return oid;
+    data::remove_rgit_file("MERGE_MSG");
+    data::remove_rgit_file("SQUASH_MSG");
+    for name in ["CHERRY_PICK_HEAD", "REVERT_HEAD"].iter() {
+        if !data::get_ref(name.to_string(), false).value.is_empty() {
+            data::delete_ref(name.to_string(), false);
+        }
+    }
+    oid
 }
 
 pub fn get_commit(oid: String) -> Commit {
-    let commit = data::get_object(get_oid(oid), "commit".to_owned());
-    let tree: String;
+    let commit = data::get_object(peel(get_oid(oid), "commit"), "commit".to_owned());
+    let mut tree = "".to_owned();
     let mut parents = vec![];
-    let message: String;
-    let mut message_start = 2;
 
+    // Headers come first, then an empty line and the message
     let lines: Vec<&str> = commit.lines().collect();
-    let mut line_items: Vec<&str> = lines[0].splitn(2, " ").collect();
-    tree = line_items[1].to_owned();
-
-    line_items = lines[1].splitn(2, " ").collect();
-    if line_items[0] == "parent" {
-        parents.push(line_items[1].to_owned());
-        message_start = 3;
-
-        //Need to be refactored later
-        let other_parents: Vec<&str> = lines[2].splitn(2, " ").collect();
-        if other_parents[0] == "parent" {
-            parents.push(other_parents[1].to_owned());
-            message_start = 4;
+    let mut message_start = lines.len();
+    for (i, line) in lines.iter().enumerate() {
+        if line.is_empty() {
+            message_start = i + 1;
+            break;
         }
-    } else {
+        let line_items: Vec<&str> = line.splitn(2, " ").collect();
+        if line_items[0] == "tree" {
+            tree = line_items[1].to_owned();
+        } else if line_items[0] == "parent" {
+            parents.push(line_items[1].to_owned());
+        }
+    }
+    if parents.is_empty() {
         parents.push("".to_owned());
     }
 
-    message = lines[message_start..].join("\n");
+    let message: String = lines[message_start..].join("\n");
 
-    return Commit {
+    Commit {
         tree,
         parents,
         message,
-    };
+    }
 }
 
 pub fn iter_commits_and_parents(mut oids: VecDeque<String>) -> Vec<String> {
@@ -141,7 +233,7 @@ pub fn iter_commits_and_parents(mut oids: VecDeque<String>) -> Vec<String> {
 
     while !oids.is_empty() {
         let oid = oids.pop_front().unwrap();
-        if oid == "" || visited.contains(&oid) {
+        if oid.is_empty() || visited.contains(&oid) {
             continue;
         }
         visited.insert(oid.clone());
@@ -150,33 +242,29 @@ pub fn iter_commits_and_parents(mut oids: VecDeque<String>) -> Vec<String> {
         let commit = get_commit(oid);
         // Deal with parent next
         oids.push_front(commit.parents[0].clone());
-        // Deal with other parent later
-        if commit.parents.len() > 1 {
-            oids.push_back(commit.parents[1].clone());
+        // Deal with other parents later
+        for parent in commit.parents[1..].iter() {
+            oids.push_back(parent.clone());
         }
     }
 
-    return oid_sequence;
+    oid_sequence
 }
 
-pub fn copy_objects_in_commits_and_parents(
-    mut oids: Vec<&String>,
-    remote_path: String,
-    push: bool,
-) {
+pub fn copy_objects_in_commits_and_parents(oids: Vec<&String>, remote_path: String, push: bool) {
     // This one is a little be different than the functions in the tutorial
     // But the end result is the same, copy all missing objects from one repo
     // to another
     let mut visited: HashSet<String> = HashSet::new();
     let mut commits = oids
         .into_iter()
-        .map(|oid| oid.clone())
+        .cloned()
         .rev()
         .collect::<VecDeque<String>>();
 
     while !commits.is_empty() {
         let oid = commits.pop_front().unwrap();
-        if oid == "" || visited.contains(&oid) {
+        if oid.is_empty() || visited.contains(&oid) {
             continue;
         }
         if push {
@@ -186,6 +274,11 @@ pub fn copy_objects_in_commits_and_parents(
         }
         visited.insert(oid.clone());
 
+        if data::get_object_type(&oid) == "tag" {
+            // Annotated tags bring the object they point to
+            commits.push_front(get_tag(oid).object);
+            continue;
+        }
         let commit = get_commit(oid.clone());
         copy_tree_objects(commit.tree, &mut visited, remote_path.clone(), push);
 
@@ -193,49 +286,158 @@ pub fn copy_objects_in_commits_and_parents(
         // Deal with parent next
         commits.push_front(parent1);
         // Deal with other parents later
-        if commit.parents.len() > 1 {
-            let parent2 = commit.parents[1].clone();
-            commits.push_back(parent2);
+        for parent in commit.parents[1..].iter() {
+            commits.push_back(parent.clone());
         }
     }
 }
 
 fn copy_tree_objects(oid: String, visited: &mut HashSet<String>, remote_path: String, push: bool) {
-    // get_tree already walks recursively the provided tree. Lets use that instead.
+    // Each tree has to be copied before reading it, so subtrees are walked
+    // one level at a time
     visited.insert(oid.clone());
-    data::fetch_object_if_missing(oid.clone(), remote_path.clone());
+    if push {
+        data::push_object(oid.clone(), remote_path.clone());
+    } else {
+        data::fetch_object_if_missing(oid.clone(), remote_path.clone());
+    }
 
-    for (path, object_id) in get_tree(oid, "./".to_owned()).iter() {
-        if visited.contains(object_id) {
+    for (object_type, object_id, _) in tree_entries(oid) {
+        if visited.contains(&object_id) {
             continue;
         }
+        if object_type == "tree" {
+            copy_tree_objects(object_id, visited, remote_path.clone(), push);
+            continue;
+        }
+        visited.insert(object_id.clone());
         if push {
-            data::push_object(object_id.clone(), remote_path.clone());
+            data::push_object(object_id, remote_path.clone());
         } else {
-            data::fetch_object_if_missing(object_id.clone(), remote_path.clone());
+            data::fetch_object_if_missing(object_id, remote_path.clone());
         }
     }
 }
 
 pub fn checkout(name: String) {
+    // Branches are checked out as such, anything else detaches HEAD
+    let detach = !is_branch(name.clone());
+    move_head(name, detach);
+}
+
+pub fn switch(name: String, detach: bool) {
+    // Like checkout, but only detaches HEAD when asked to
+    assert!(
+        detach || is_branch(name.clone()),
+        "{} is not a branch, use --detach to switch to a commit",
+        name
+    );
+    move_head(name, detach);
+}
+
+fn move_head(name: String, detach: bool) {
+    let old_head = data::get_ref("HEAD".to_owned(), true).value;
+    let mut from = get_branch_name();
+    if from.is_empty() {
+        from = old_head.clone();
+    }
+    let reason = format!("checkout: moving from {} to {}", from, name);
     let oid = get_oid(name.clone());
     let commit = get_commit(oid.clone());
-    let head;
     read_tree(commit.tree);
 
-    if is_branch(name.clone()) {
-        head = data::RefValue {
+    let head = if !detach {
+        data::RefValue {
             symbolic: true,
             value: format!("refs/heads/{}", name),
-        };
+        }
     } else {
-        head = data::RefValue {
+        data::RefValue {
             symbolic: false,
-            value: oid,
-        };
+            value: oid.clone(),
+        }
+    };
+
+    let was_detached = get_branch_name().is_empty();
+    data::update_ref("HEAD".to_owned(), head, false, &reason);
+    if was_detached && !old_head.is_empty() {
+        warn_lost_commits(old_head);
+    }
+    if detach {
+        let subject = commit.message.lines().next().unwrap_or("").to_owned();
+        println!(
+            "Note: you are now in 'detached HEAD' state, at {} {}",
+            &oid[0..10],
+            subject
+        );
+        println!("Commits made here do not belong to any branch and can get lost when");
+        println!("switching away. To keep them, create a branch with: switch -c <name>");
     }
+}
 
-    data::update_ref("HEAD".to_owned(), head, false);
+fn warn_lost_commits(old_head: String) {
+    // Commits from a detached HEAD that no ref leads to anymore
+    let refs: Vec<String> = data::iter_refs("", true)
+        .into_iter()
+        .map(|(_, reference)| reference.value)
+        .filter(|value| !value.is_empty())
+        .collect();
+    let lost = iter_commits_in_range(vec![old_head.clone()], refs);
+    if lost.is_empty() {
+        return;
+    }
+    println!(
+        "Warning: you are leaving {} commit(s) behind, not connected to any branch:",
+        lost.len()
+    );
+    for oid in lost.iter() {
+        let message = get_commit(oid.clone()).message;
+        println!("  {} {}", &oid[0..10], message.lines().next().unwrap_or(""));
+    }
+    println!(
+        "If you want to keep them, create a branch now with: branch <name> {}",
+        old_head
+    );
+}
+
+pub fn checkout_paths(name: String, paths: &Vec<String>) {
+    // Overwrites the given paths in the working tree with their version in
+    // the index, or in the commit (updating the index too) if one is given
+    let mut index = data::get_index();
+    let files = if name.is_empty() {
+        diff::filter_tree(index.clone(), paths)
+    } else {
+        diff::filter_tree(get_commit_tree(name.clone()), paths)
+    };
+    for path in paths {
+        let path = path
+            .trim_start_matches("./")
+            .trim_end_matches("/")
+            .to_owned();
+        let matched = files
+            .keys()
+            .any(|file| *file == path || file.starts_with(&format!("{}/", path)));
+        assert!(
+            matched || path.is_empty() || path == ".",
+            "Unknown path {}",
+            path
+        );
+    }
+
+    let mut conflicts = data::get_index_conflicts();
+    for (path, oid) in files.iter() {
+        let mut dirs = Path::new(path).ancestors();
+        dirs.next();
+        fs::create_dir_all(dirs.next().unwrap()).expect("Cannot create required dirs");
+        fs::write(path, data::get_object(oid.clone(), "".to_owned()))
+            .expect("Cannot write required object");
+        if !name.is_empty() {
+            index.insert(path.clone(), oid.clone());
+            conflicts.remove(path);
+        }
+    }
+    data::set_index(index);
+    data::set_index_conflicts(conflicts);
 }
 
 pub fn create_tag(name: String, oid: String) {
@@ -246,28 +448,193 @@ pub fn create_tag(name: String, oid: String) {
             symbolic: false,
         },
         true,
+        "",
     );
 }
 
-pub fn get_oid(mut name: String) -> String {
-    if name == "@".to_owned() {
-        name = "HEAD".to_owned();
+pub fn create_annotated_tag(name: String, oid: String, message: String, signed: bool) {
+    let (timestamp, timezone) = data::get_timestamp();
+    let mut tag = format!(
+        "object {}\ntype {}\ntag {}\ntagger {} {} {}\n\n{}\n",
+        oid,
+        data::get_object_type(&oid),
+        name,
+        data::get_identity(),
+        timestamp,
+        timezone,
+        message.trim_end()
+    );
+    if signed {
+        tag = sign::add_signature(&tag);
     }
+    let tag_oid = data::hash_object(&tag.into_bytes(), "tag".to_owned());
+    create_tag(name, tag_oid);
+}
 
-    let refs_to_try: [String; 4] = [
-        format!("{}", name),
-        format!("refs/{}", name),
-        format!("refs/tags/{}", name),
-        format!("refs/heads/{}", name),
-    ];
+pub fn get_tag(oid: String) -> Tag {
+    let content = data::get_object(oid, "tag".to_owned());
+    let mut tag = Tag {
+        object: "".to_owned(),
+        object_type: "".to_owned(),
+        name: "".to_owned(),
+        tagger: "".to_owned(),
+        message: "".to_owned(),
+    };
 
-    for reference in refs_to_try.iter() {
-        let found = data::get_ref(reference.clone(), false);
-        if found.value != "" {
-            return data::get_ref(reference.clone(), true).value;
-        } else {
+    // Same layout as commits, headers then an empty line and the message
+    let lines: Vec<&str> = content.lines().collect();
+    let mut message_start = lines.len();
+    for (i, line) in lines.iter().enumerate() {
+        if line.is_empty() {
+            message_start = i + 1;
+            break;
+        }
+        let line_items: Vec<&str> = line.splitn(2, " ").collect();
+        let value = line_items.get(1).unwrap_or(&"").to_string();
+        match line_items[0] {
+            "object" => tag.object = value,
+            "type" => tag.object_type = value,
+            "tag" => tag.name = value,
+            "tagger" => tag.tagger = value,
+            _ => (),
+        }
+    }
+    tag.message = lines[message_start..].join("\n");
+    tag
+}
+
+pub fn list_tags(pattern: &str) -> Vec<String> {
+    // Names of the tags matching the pattern ("*" and "?" wildcards), sorted
+    let mut tags: Vec<String> = data::iter_refs("refs/tags/", false)
+        .into_iter()
+        .map(|(name, _)| name.trim_start_matches("refs/tags/").to_owned())
+        .filter(|name| pattern.is_empty() || match_pattern(pattern, name))
+        .collect();
+    tags.sort();
+    tags
+}
+
+pub fn match_pattern(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    match_chars(&pattern, &name)
+}
+
+fn match_chars(pattern: &[char], name: &[char]) -> bool {
+    if pattern.is_empty() {
+        return name.is_empty();
+    }
+    match pattern[0] {
+        // Either "*" matches nothing, or it eats one more character
+        '*' => {
+            match_chars(&pattern[1..], name)
+                || (!name.is_empty() && match_chars(pattern, &name[1..]))
+        }
+        '?' => !name.is_empty() && match_chars(&pattern[1..], &name[1..]),
+        c => !name.is_empty() && name[0] == c && match_chars(&pattern[1..], &name[1..]),
+    }
+}
+
+pub fn get_oid(name: String) -> String {
+    // Tags are followed to the object they point to, unless the tag object
+    // itself is asked for with its oid or "^{tag}"
+    let oid = get_object_oid(name.clone());
+    if name == oid || name.ends_with("^{tag}") {
+        return oid;
+    }
+    peel(oid, "")
+}
+
+pub fn get_object_oid(name: String) -> String {
+    // Besides names and oids, this understands most of Git's revision syntax:
+    //   <rev>~<n>     n-th ancestor, following the first parents
+    //   <rev>^<n>     n-th parent ("^" is the first one, "^0" the commit itself)
+    //   <rev>^{type}  the object of that type it leads to, "^{}" peels tags
+    //   <rev>:<path>  a file or directory of the commit, ":<path>" is the index
+    if let Some(colon) = find_outside_braces(&name, &[':']) {
+        let path = name[colon + 1..].trim_start_matches("./");
+        if colon == 0 {
+            return data::get_index()
+                .get(path)
+                .cloned()
+                .unwrap_or_else(|| panic!("Path {} is not in the index", path));
+        }
+        let tree = peel(get_object_oid(name[..colon].to_owned()), "tree");
+        return get_path_oid(tree, path);
+    }
+
+    let end = find_outside_braces(&name, &['~', '^']).unwrap_or(name.len());
+    let mut oid = get_base_oid(name[..end].to_owned());
+    let mut rest = &name[end..];
+    while !rest.is_empty() {
+        let operator = &rest[0..1];
+        rest = &rest[1..];
+        if operator == "^" && rest.starts_with("{") {
+            let close = rest
+                .find("}")
+                .unwrap_or_else(|| panic!("Invalid revision {}", name));
+            oid = peel(oid, &rest[1..close]);
+            rest = &rest[close + 1..];
             continue;
         }
+
+        let digits: String = rest.chars().take_while(|c| c.is_ascii_digit()).collect();
+        rest = &rest[digits.len()..];
+        let count: usize = if digits.is_empty() {
+            1
+        } else {
+            digits.parse().unwrap()
+        };
+        oid = peel(oid, "commit");
+        if operator == "~" {
+            for _ in 0..count {
+                oid = get_commit(oid.clone()).parents[0].clone();
+                assert!(!oid.is_empty(), "{} goes past the first commit", name);
+            }
+        } else if count > 0 {
+            let parents = get_commit(oid).parents;
+            assert!(
+                count <= parents.len() && !parents[0].is_empty(),
+                "{} does not exist, the commit has {} parent(s)",
+                name,
+                parents.len()
+            );
+            oid = parents[count - 1].clone();
+        }
+    }
+    oid
+}
+
+fn get_base_oid(mut name: String) -> String {
+    // The part of a revision before any "~", "^" or ":"
+    for suffix in ["@{u}", "@{upstream}"].iter() {
+        if name.ends_with(suffix) {
+            let mut branch = name.trim_end_matches(suffix).to_owned();
+            if branch.is_empty() {
+                branch = get_branch_name();
+                assert!(!branch.is_empty(), "HEAD is detached, it has no upstream");
+            }
+            let upstream = get_upstream(&branch);
+            assert!(
+                !upstream.is_empty(),
+                "No upstream configured for branch {}",
+                branch
+            );
+            return data::get_ref(upstream, true).value;
+        }
+    }
+    if name.contains("@{") && name.ends_with("}") {
+        return get_reflog_oid(name);
+    }
+    if name == "@" {
+        name = "HEAD".to_owned();
+    }
+
+    let reference = get_ref_name(&name);
+    if !reference.is_empty() {
+        // MERGE_HEAD has one line per merged commit, the first one is used
+        let value = data::get_ref(reference, true).value;
+        return value.lines().next().unwrap_or("").to_owned();
     }
 
     let mut is_hex = true;
@@ -281,18 +648,382 @@ pub fn get_oid(mut name: String) -> String {
         return name;
     }
 
-    panic!(format!("Unknown name {}", name));
+    // Abbreviated oids have to match a single object
+    if name.len() >= 4 && is_hex {
+        let mut matches = data::find_objects(&name.to_lowercase());
+        matches.sort();
+        assert!(
+            matches.len() < 2,
+            "Short oid {} is ambiguous, it could be: {}",
+            name,
+            matches.join(", ")
+        );
+        if matches.len() == 1 {
+            return matches.pop().unwrap();
+        }
+    }
+
+    panic!("Unknown name {}", name);
 }
 
-pub fn create_branch(name: String, oid: String) {
-    data::update_ref(
+fn find_outside_braces(name: &str, wanted: &[char]) -> Option<usize> {
+    // Position of the first wanted character that is not inside "{...}"
+    let mut depth = 0;
+    for (i, c) in name.char_indices() {
+        if c == '{' {
+            depth += 1;
+        } else if c == '}' && depth > 0 {
+            depth -= 1;
+        } else if depth == 0 && wanted.contains(&c) {
+            return Some(i);
+        }
+    }
+    None
+}
+
+pub fn peel(oid: String, target: &str) -> String {
+    // Follows an object until reaching one of the wanted type ("" stops at
+    // the first object that is not a tag)
+    let mut oid = oid;
+    loop {
+        let object_type = data::get_object_type(&oid);
+        if object_type == target || (target.is_empty() && object_type != "tag") {
+            return oid;
+        }
+        match object_type.as_str() {
+            "tag" => oid = get_tag(oid).object,
+            "commit" if target == "tree" => oid = get_commit(oid).tree,
+            _ => panic!("{} is a {}, not a {}", oid, object_type, target),
+        }
+    }
+}
+
+fn get_path_oid(tree: String, path: &str) -> String {
+    let mut oid = tree.clone();
+    for name in path.split("/").filter(|name| !name.is_empty()) {
+        let entry = tree_entries(oid.clone())
+            .into_iter()
+            .find(|(_, _, entry_name)| entry_name == name);
+        match entry {
+            Some((_, entry_oid, _)) => oid = entry_oid,
+            None => panic!("Path {} does not exist in {}", path, tree),
+        }
+    }
+    oid
+}
+
+pub fn get_revision_range(revisions: Vec<String>) -> (Vec<String>, Vec<String>) {
+    // Splits revisions into the commits to include and to exclude:
+    // "A..B" is B without A, "A...B" is A and B without their merge bases
+    // and "^A" excludes A. A missing side of ".." or "..." is HEAD.
+    let mut include = vec![];
+    let mut exclude = vec![];
+    for revision in revisions {
+        if let Some(start) = revision.find("..") {
+            let symmetric = revision[start..].starts_with("...");
+            let end = if symmetric { start + 3 } else { start + 2 };
+            let from = if start == 0 { "@" } else { &revision[..start] };
+            let to = if end == revision.len() {
+                "@"
+            } else {
+                &revision[end..]
+            };
+            let from = peel(get_oid(from.to_owned()), "commit");
+            let to = peel(get_oid(to.to_owned()), "commit");
+            if symmetric {
+                exclude.extend(get_merge_bases(from.clone(), to.clone()));
+                include.push(from);
+            } else {
+                exclude.push(from);
+            }
+            include.push(to);
+        } else if let Some(revision) = revision.strip_prefix('^') {
+            exclude.push(get_oid(revision.to_owned()));
+        } else {
+            include.push(get_oid(revision));
+        }
+    }
+    (include, exclude)
+}
+
+pub fn iter_commits_in_range(include: Vec<String>, exclude: Vec<String>) -> Vec<String> {
+    let excluded: HashSet<String> =
+        iter_commits_and_parents(exclude.into_iter().map(|oid| peel(oid, "commit")).collect())
+            .into_iter()
+            .collect();
+    iter_commits_and_parents(include.into_iter().map(|oid| peel(oid, "commit")).collect())
+        .into_iter()
+        .filter(|oid| !excluded.contains(oid))
+        .collect()
+}
+
+pub fn get_ref_name(name: &str) -> String {
+    // Full name of the ref a short name refers to (ex: "refs/heads/master"
+    // for "master"), empty if there is none
+    let refs_to_try: [String; 5] = [
+        name.to_string(),
+        format!("refs/{}", name),
+        format!("refs/tags/{}", name),
         format!("refs/heads/{}", name),
+        format!("refs/remotes/{}", name),
+    ];
+
+    for reference in refs_to_try.iter() {
+        let found = data::get_ref(reference.clone(), false);
+        if !found.value.is_empty() {
+            return reference.clone();
+        }
+    }
+    "".to_owned()
+}
+
+fn get_reflog_oid(name: String) -> String {
+    // "<ref>@{n}" is the value the ref had n changes ago and "<ref>@{<date>}"
+    // the value it had at that time. Without a ref, the current branch is used.
+    let at = name.rfind("@{").unwrap();
+    let spec = &name[at + 2..name.len() - 1];
+    let mut reference = name[..at].to_owned();
+    if reference.is_empty() {
+        let head = data::get_ref("HEAD".to_owned(), false);
+        reference = if head.symbolic {
+            head.value
+        } else {
+            "HEAD".to_owned()
+        };
+    } else {
+        reference = get_ref_name(&reference);
+        assert!(!reference.is_empty(), "Unknown name {}", name);
+    }
+
+    let entries = data::read_reflog(&reference);
+    assert!(!entries.is_empty(), "There is no log for {}", reference);
+    if let Ok(count) = spec.parse::<usize>() {
+        assert!(
+            count < entries.len(),
+            "Log for {} only has {} entries",
+            reference,
+            entries.len()
+        );
+        return entries[entries.len() - 1 - count].new.clone();
+    }
+
+    let time = parse_date(spec);
+    for entry in entries.iter().rev() {
+        if entry.timestamp <= time {
+            return entry.new.clone();
+        }
+    }
+    // The date is older than the log, so go with where it started
+    eprintln!("warning: Log for {} does not go back that far", reference);
+    if entries[0].old != "0".repeat(40) {
+        return entries[0].old.clone();
+    }
+    entries[0].new.clone()
+}
+
+fn parse_date(spec: &str) -> i64 {
+    // Understands "now", "yesterday", "<n> <unit>s ago" (or "<n>.<unit>s.ago"),
+    // "YYYY-MM-DD" and "YYYY-MM-DD HH:MM:SS"
+    let (now, _) = data::get_timestamp();
+    let words: Vec<&str> = spec
+        .split(['.', ' '])
+        .filter(|word| !word.is_empty())
+        .collect();
+    match words.as_slice() {
+        ["now"] => return now,
+        ["yesterday"] => return now - 86400,
+        [count, unit, "ago"] => {
+            let count: i64 = count
+                .parse()
+                .unwrap_or_else(|_| panic!("Invalid date: {}", spec));
+            let seconds = match unit.trim_end_matches("s") {
+                "second" => 1,
+                "minute" => 60,
+                "hour" => 3600,
+                "day" => 86400,
+                "week" => 604800,
+                "month" => 2592000,
+                "year" => 31536000,
+                _ => panic!("Invalid date: {}", spec),
+            };
+            return now - count * seconds;
+        }
+        _ => (),
+    }
+
+    let date: Vec<i32> = words[0]
+        .split("-")
+        .map(|item| {
+            item.parse()
+                .unwrap_or_else(|_| panic!("Invalid date: {}", spec))
+        })
+        .collect();
+    let time: Vec<i32> = match words.get(1) {
+        Some(time) => time
+            .split(":")
+            .map(|item| {
+                item.parse()
+                    .unwrap_or_else(|_| panic!("Invalid date: {}", spec))
+            })
+            .collect(),
+        None => vec![],
+    };
+    assert!(date.len() == 3, "Invalid date: {}", spec);
+    unsafe {
+        let mut tm: libc::tm = std::mem::zeroed();
+        tm.tm_year = date[0] - 1900;
+        tm.tm_mon = date[1] - 1;
+        tm.tm_mday = date[2];
+        tm.tm_hour = *time.first().unwrap_or(&0);
+        tm.tm_min = *time.get(1).unwrap_or(&0);
+        tm.tm_sec = *time.get(2).unwrap_or(&0);
+        tm.tm_isdst = -1;
+        libc::mktime(&mut tm) as i64
+    }
+}
+
+pub fn create_branch(name: String, oid: String, force: bool) {
+    // With force an existing branch is moved to the commit instead, unless it
+    // is the current one
+    let reference = format!("refs/heads/{}", name);
+    let exists = !data::get_ref(reference.clone(), false).value.is_empty();
+    assert!(
+        force || !exists,
+        "A branch named {} already exists, use -f to move it",
+        name
+    );
+    assert!(
+        !exists || name != get_branch_name(),
+        "Cannot move the current branch {}, use reset instead",
+        name
+    );
+    let reason = if exists {
+        format!("branch: Reset to {}", oid)
+    } else {
+        format!("branch: Created from {}", oid)
+    };
+    data::update_ref(
+        reference,
         data::RefValue {
             value: oid,
             symbolic: false,
         },
         true,
+        &reason,
+    );
+}
+
+pub fn set_upstream(branch: &str, upstream: &str) {
+    // Stores the full name of the ref the branch tracks in the config, an
+    // empty upstream removes it
+    let mut values = data::get_config();
+    let key = format!("branch.{}.upstream", branch);
+    if upstream.is_empty() {
+        values.remove(&key);
+    } else {
+        assert!(is_branch(branch.to_owned()), "Branch {} not found", branch);
+        let reference = get_ref_name(upstream);
+        assert!(!reference.is_empty(), "Upstream {} not found", upstream);
+        // Only branches move with fetch and pull, a tag would never change
+        assert!(
+            reference.starts_with("refs/heads/") || reference.starts_with("refs/remotes/"),
+            "The upstream {} ({}) is not a local or remote-tracking branch",
+            upstream,
+            reference
+        );
+        values.insert(key, reference);
+    }
+    data::set_config(values);
+}
+
+pub fn get_upstream(branch: &str) -> String {
+    data::get_config_value(&format!("branch.{}.upstream", branch))
+}
+
+pub fn get_ahead_behind(local: String, upstream: String) -> (usize, usize) {
+    // Commits only the local branch has and commits only the upstream has
+    let ahead = iter_commits_in_range(vec![local.clone()], vec![upstream.clone()]);
+    let behind = iter_commits_in_range(vec![upstream], vec![local]);
+    (ahead.len(), behind.len())
+}
+
+pub fn delete_branch(name: String, force: bool) -> String {
+    // Returns the commit the branch was at. Without force, only branches
+    // merged into HEAD can be deleted.
+    let reference = format!("refs/heads/{}", name);
+    let oid = data::get_ref(reference.clone(), false).value;
+    assert!(!oid.is_empty(), "Branch {} not found", name);
+    assert!(
+        name != get_branch_name(),
+        "Cannot delete the branch {} which is checked out",
+        name
+    );
+    let head = data::get_ref("HEAD".to_owned(), true).value;
+    assert!(
+        force || (!head.is_empty() && is_ancestor_of(head, oid.clone())),
+        "The branch {} is not fully merged, use -D to delete it anyway",
+        name
+    );
+    data::delete_ref(reference, false);
+    set_upstream(&name, "");
+    oid
+}
+
+pub fn rename_branch(old_name: String, new_name: String, force: bool) {
+    // The branch keeps its reflog and HEAD follows it if it was checked out
+    let old_ref = format!("refs/heads/{}", old_name);
+    let new_ref = format!("refs/heads/{}", new_name);
+    let oid = data::get_ref(old_ref.clone(), false).value;
+    assert!(!oid.is_empty(), "Branch {} not found", old_name);
+    if old_name == new_name {
+        return;
+    }
+    assert!(
+        force || data::get_ref(new_ref.clone(), false).value.is_empty(),
+        "A branch named {} already exists, use -M to overwrite it",
+        new_name
+    );
+
+    let reason = format!("Branch: renamed {} to {}", old_ref, new_ref);
+    let log = data::read_reflog(&old_ref);
+    let mut transaction = data::RefTransaction::new();
+    transaction.delete(old_ref.clone(), false, Some(oid.clone()));
+    transaction.update(
+        new_ref.clone(),
+        data::RefValue {
+            value: oid,
+            symbolic: false,
+        },
+        false,
+        if force { None } else { Some("".to_owned()) },
+        &reason,
     );
+    if get_branch_name() == old_name {
+        transaction.update(
+            "HEAD".to_owned(),
+            data::RefValue {
+                value: new_ref.clone(),
+                symbolic: true,
+            },
+            false,
+            None,
+            &reason,
+        );
+    }
+    transaction.commit();
+
+    // Only the rename entry is kept from the log of a branch overwritten by it
+    let mut new_log = log;
+    new_log.extend(data::read_reflog(&new_ref).pop());
+    data::write_reflog(&new_ref, &new_log);
+
+    let mut values = data::get_config();
+    let upstream = values.remove(&format!("branch.{}.upstream", old_name));
+    values.remove(&format!("branch.{}.upstream", new_name));
+    if let Some(upstream) = upstream {
+        values.insert(format!("branch.{}.upstream", new_name), upstream);
+    }
+    data::set_config(values);
 }
 
 pub fn init() -> std::io::Result<()> {
@@ -304,10 +1035,20 @@ pub fn init() -> std::io::Result<()> {
             value: "refs/heads/master".to_owned(),
         },
         true,
+        "",
     );
     Ok(())
 }
 
+pub fn assert_work_tree(command: &str) {
+    // Bare repositories (clone --bare) have no working tree to use
+    assert!(
+        data::get_config_value("core.bare") != "true",
+        "{} needs a working tree, which this bare repository does not have",
+        command
+    );
+}
+
 pub fn get_branch_name() -> String {
     let head = data::get_ref("HEAD".to_owned(), false);
     if !head.symbolic {
@@ -315,7 +1056,7 @@ pub fn get_branch_name() -> String {
     }
     assert!(head.value.starts_with("refs/heads/"));
     let ref_items: Vec<&str> = head.value.splitn(3, "/").collect();
-    return (*ref_items.last().unwrap()).to_owned();
+    (*ref_items.last().unwrap()).to_owned()
 }
 
 pub fn iter_branch_names() -> Vec<String> {
@@ -328,6 +1069,7 @@ pub fn iter_branch_names() -> Vec<String> {
 }
 
 pub fn reset(oid: String) {
+    let reason = format!("reset: moving to {}", oid);
     data::update_ref(
         "HEAD".to_owned(),
         data::RefValue {
@@ -335,18 +1077,61 @@ pub fn reset(oid: String) {
             value: oid,
         },
         true,
+        &reason,
     )
 }
 
-pub fn merge(oid: String) {
+pub fn merge(names: Vec<String>, options: &MergeOptions) -> Vec<String> {
     let head = data::get_ref("HEAD".to_owned(), true);
-    assert!(head.value != "");
+    assert!(!head.value.is_empty());
+    assert!(
+        data::get_ref("MERGE_HEAD".to_owned(), false)
+            .value
+            .is_empty(),
+        "A merge is already in progress, use --continue or --abort"
+    );
+
+    let mut others = vec![];
+    for name in names {
+        let oid = get_oid(name.clone());
+        if is_ancestor_of(head.value.clone(), oid.clone()) {
+            println!("Already up to date with {}", name);
+        } else {
+            others.push((name, oid));
+        }
+    }
+    if others.is_empty() {
+        println!("Already up to date");
+        return vec![];
+    }
+    // Check before anything is written, an octopus merge never fast-forwards
+    assert!(
+        !options.ff_only
+            || (others.len() == 1 && is_ancestor_of(others[0].1.clone(), head.value.clone())),
+        "Not possible to fast-forward, aborting"
+    );
+
+    data::update_ref(
+        "ORIG_HEAD".to_owned(),
+        data::RefValue {
+            symbolic: false,
+            value: head.value.clone(),
+        },
+        false,
+        "",
+    );
+
+    if others.len() > 1 {
+        merge_octopus(others, head.value, options);
+        return vec![];
+    }
 
-    let merge_base = get_merge_base(oid.clone(), head.value.clone());
+    let (name, oid) = others.pop().unwrap();
     let c_other = get_commit(oid.clone());
+    let can_fast_forward = is_ancestor_of(oid.clone(), head.value.clone());
 
     // Handle fast-forward merge
-    if merge_base == head.value {
+    if can_fast_forward && !options.no_ff && !options.squash {
         read_tree(c_other.tree);
         data::update_ref(
             "HEAD".to_owned(),
@@ -355,57 +1140,287 @@ pub fn merge(oid: String) {
                 value: oid,
             },
             true,
+            &format!("merge {}: Fast-forward", name),
         );
         println!("Fast-forward merge, no need to commit");
+        return vec![];
+    }
+
+    start_merge(&[(name, oid.clone())], options.squash);
+    let c_head = get_commit(head.value.clone());
+    let conflicts = if options.strategy == "ours" {
+        // The other side is recorded as merged, but its changes are ignored
+        vec![]
+    } else {
+        let base_tree = get_merge_base_tree(head.value, oid.clone(), &options.strategy);
+        read_tree_merged(base_tree, c_head.tree, c_other.tree, &options.favor)
+    };
+    if conflicts.is_empty() && options.no_ff && !options.squash {
+        // The merge commit is created right away
+        println!("Merge made by the '{}' strategy.", options.strategy);
+        println!("{}", commit("", false));
+    } else if conflicts.is_empty() {
+        println!("Merged in working tree");
+        println!("Please commit");
+    } else {
+        for (_, message) in conflicts.iter() {
+            println!("{}", message);
+        }
+        println!("Automatic merge failed; fix conflicts and then commit the result.");
+    }
+    conflicts.into_iter().map(|(path, _)| path).collect()
+}
+
+fn merge_octopus(others: Vec<(String, String)>, head: String, options: &MergeOptions) {
+    // Each commit is merged in turn on top of the result of the previous
+    // ones. This is done in memory, since there is no way to resolve the
+    // conflicts of an octopus merge by hand.
+    let mut tree = get_commit(head.clone()).tree;
+    if options.strategy != "ours" {
+        for (name, oid) in others.iter() {
+            let base_tree = get_merge_base_tree(head.clone(), oid.clone(), &options.strategy);
+            let other_tree = get_commit(oid.clone()).tree;
+            let (merged, conflicts) =
+                merge_tree_objects(base_tree, tree, other_tree, &options.favor);
+            assert!(
+                conflicts.is_empty(),
+                "Merge with {} failed, octopus merges cannot have conflicts: {:?}",
+                name,
+                conflicts
+            );
+            tree = merged;
+        }
+    }
+
+    start_merge(&others, options.squash);
+    if options.strategy != "ours" {
+        read_tree(tree);
+    }
+    if options.no_ff && !options.squash {
+        println!("Merge made by the 'octopus' strategy.");
+        println!("{}", commit("", false));
+        return;
+    }
+    println!("Merged in working tree");
+    println!("Please commit");
+}
+
+fn start_merge(others: &[(String, String)], squash: bool) {
+    let names: Vec<String> = others
+        .iter()
+        .map(|(name, _)| format!("'{}'", name))
+        .collect();
+    let listed = if names.len() == 1 {
+        names[0].clone()
+    } else {
+        format!(
+            "{} and {}",
+            names[..names.len() - 1].join(", "),
+            names[names.len() - 1]
+        )
+    };
+
+    if squash {
+        // The changes are staged to be committed as a regular commit, so
+        // there is no MERGE_HEAD
+        data::write_rgit_file("SQUASH_MSG", &format!("Squashed commit of {}", listed));
         return;
     }
 
+    let all_branches = others.iter().all(|(name, _)| is_branch(name.clone()));
+    let kind = match (all_branches, others.len()) {
+        (true, 1) => "branch",
+        (true, _) => "branches",
+        (false, 1) => "commit",
+        (false, _) => "commits",
+    };
+
+    // Keep what is needed to go back to the state before the merge
+    save_pre_merge_state();
+    data::write_rgit_file("MERGE_MSG", &format!("Merge {} {}", kind, listed));
+    // Like in Git, MERGE_HEAD has one line for each commit being merged
+    let oids: Vec<String> = others.iter().map(|(_, oid)| oid.clone()).collect();
     data::update_ref(
         "MERGE_HEAD".to_owned(),
         data::RefValue {
             symbolic: false,
-            value: oid,
+            value: oids.join("\n"),
         },
         true,
+        "",
     );
+}
 
-    let c_base = get_commit(merge_base);
-    let c_head = get_commit(head.value);
-    read_tree_merged(c_base.tree, c_head.tree, c_other.tree);
-    println!("Merged in working tree");
-    println!("Please commit");
+pub fn merge_abort() {
+    assert!(
+        !data::get_ref("MERGE_HEAD".to_owned(), false)
+            .value
+            .is_empty(),
+        "There is no merge to abort"
+    );
+    let orig_head = data::get_ref("ORIG_HEAD".to_owned(), false).value;
+    let orig_index = data::get_ref("MERGE_ORIG_INDEX".to_owned(), false).value;
+    let orig_working_tree = data::get_ref("MERGE_ORIG_WORKTREE".to_owned(), false).value;
+
+    read_tree(orig_working_tree);
+    data::set_index(get_tree(orig_index, "".to_owned()));
+    data::update_ref(
+        "HEAD".to_owned(),
+        data::RefValue {
+            symbolic: false,
+            value: orig_head,
+        },
+        true,
+        "merge: abort",
+    );
+    clear_merge_state();
+}
+
+pub fn merge_continue() -> String {
+    assert!(
+        !data::get_ref("MERGE_HEAD".to_owned(), false)
+            .value
+            .is_empty(),
+        "There is no merge in progress"
+    );
+    commit("", false)
+}
+
+fn save_pre_merge_state() {
+    let index_tree = write_tree_from(&data::get_index());
+    let working_tree = write_tree_from(&get_working_tree());
+    for (name, tree) in [
+        ("MERGE_ORIG_INDEX", index_tree),
+        ("MERGE_ORIG_WORKTREE", working_tree),
+    ]
+    .iter()
+    {
+        data::update_ref(
+            name.to_string(),
+            data::RefValue {
+                symbolic: false,
+                value: tree.clone(),
+            },
+            false,
+            "",
+        );
+    }
+}
+
+fn clear_merge_state() {
+    for name in ["MERGE_HEAD", "MERGE_ORIG_INDEX", "MERGE_ORIG_WORKTREE"].iter() {
+        if !data::get_ref(name.to_string(), false).value.is_empty() {
+            data::delete_ref(name.to_string(), false);
+        }
+    }
+    data::remove_rgit_file("MERGE_MSG");
 }
 
 pub fn get_merge_base(commit1: String, commit2: String) -> String {
-    let mut commit1_deq = VecDeque::new();
-    commit1_deq.push_front(commit1);
+    get_merge_bases(commit1, commit2)
+        .first()
+        .cloned()
+        .unwrap_or("".to_owned())
+}
+
+pub fn get_merge_bases(commit1: String, commit2: String) -> Vec<String> {
+    get_best_common_ancestors(vec![commit1], commit2)
+}
+
+fn get_best_common_ancestors(commits1: Vec<String>, commit2: String) -> Vec<String> {
+    // The best common ancestors are the ones that cannot be reached from any
+    // other common ancestor. In criss-cross histories there is more than one.
+    // The first side can be several commits, as if they had been merged.
+    let commit1_deq: VecDeque<String> = commits1.into_iter().collect();
 
     let mut commit2_deq = VecDeque::new();
     commit2_deq.push_front(commit2);
 
-    let parents1 = iter_commits_and_parents(commit1_deq);
+    let parents1: HashSet<String> = iter_commits_and_parents(commit1_deq).into_iter().collect();
+    let common: Vec<String> = iter_commits_and_parents(commit2_deq)
+        .into_iter()
+        .filter(|oid| parents1.contains(oid))
+        .collect();
 
-    for oid in iter_commits_and_parents(commit2_deq) {
-        if parents1.contains(&oid) {
-            return oid;
+    let mut older = VecDeque::new();
+    for oid in common.iter() {
+        for parent in get_commit(oid.clone()).parents {
+            older.push_back(parent);
         }
     }
+    let reachable: HashSet<String> = iter_commits_and_parents(older).into_iter().collect();
 
-    return "".to_owned();
+    common
+        .into_iter()
+        .filter(|oid| !reachable.contains(oid))
+        .collect()
 }
 
-fn is_ignored(path: &String) -> bool {
-    if path.contains(".rgit") {
-        true
-    } else {
-        false
+fn get_merge_base_tree(commit1: String, commit2: String, strategy: &str) -> String {
+    let bases = get_merge_bases(commit1, commit2);
+    if bases.is_empty() {
+        return "".to_owned();
     }
+    if strategy == "resolve" {
+        return get_commit(bases[0].clone()).tree;
+    }
+    get_virtual_base_tree(&bases)
+}
+
+fn get_virtual_base_tree(bases: &[String]) -> String {
+    // With the recursive strategy, all the merge bases are merged together
+    // into a virtual base. It is never written as a commit: its history is
+    // just the bases merged so far, so the common ancestors with the next
+    // one are found from them directly.
+    let mut tree = get_commit(bases[0].clone()).tree;
+    for i in 1..bases.len() {
+        let inner_bases = get_best_common_ancestors(bases[..i].to_vec(), bases[i].clone());
+        let base_tree = if inner_bases.is_empty() {
+            "".to_owned()
+        } else {
+            get_virtual_base_tree(&inner_bases)
+        };
+        tree = merge_tree_objects(base_tree, tree, get_commit(bases[i].clone()).tree, "").0;
+    }
+    tree
+}
+
+fn merge_tree_objects(
+    base_tree: String,
+    head_tree: String,
+    other_tree: String,
+    favor: &str,
+) -> (String, Vec<String>) {
+    // Same as read_tree_merged, but the result is only written as a new tree,
+    // conflict markers included
+    let merged = diff::merge_trees(
+        get_tree(base_tree, "".to_owned()),
+        get_tree(head_tree, "".to_owned()),
+        get_tree(other_tree, "".to_owned()),
+        "merge",
+        favor,
+    );
+    let mut files = HashMap::new();
+    let mut conflicts = vec![];
+    for (path, blob) in merged {
+        if !blob.conflict.is_empty() {
+            conflicts.push(path);
+        }
+        let oid = data::hash_object(&blob.content.into_bytes(), "blob".to_owned());
+        files.insert(blob.path, oid);
+    }
+    conflicts.sort();
+    (write_tree_from(&files), conflicts)
+}
+
+fn is_ignored(path: &str) -> bool {
+    path.contains(".rgit")
 }
 
 fn tree_entries(oid: String) -> Vec<(String, String, String)> {
     let mut entries: Vec<(String, String, String)> = vec![];
 
-    if oid == "".to_owned() {
+    if oid.is_empty() {
         return entries;
     }
 
@@ -418,7 +1433,7 @@ fn tree_entries(oid: String) -> Vec<(String, String, String)> {
             items[2].to_owned(), // name
         ));
     }
-    return entries;
+    entries
 }
 
 pub fn get_tree(oid: String, base_path: String) -> HashMap<String, String> {
@@ -429,9 +1444,9 @@ pub fn get_tree(oid: String, base_path: String) -> HashMap<String, String> {
         assert!(entry.2 != "..");
         assert!(entry.2 != ".");
         let path = base_path.clone() + entry.2.as_str();
-        if entry.0 == "blob".to_owned() {
+        if entry.0 == "blob" {
             result.insert(path.clone(), entry.1.clone());
-        } else if entry.0 == "tree".to_owned() {
+        } else if entry.0 == "tree" {
             result.extend(get_tree(entry.1, format!("{}/", path)));
         } else {
             panic!("Unknown tree entry: {}", entry.0);
@@ -448,7 +1463,7 @@ pub fn get_working_tree() -> HashMap<String, String> {
         let relative_path = item.path().strip_prefix("./").unwrap();
         let metadata = item.metadata().unwrap();
         let path = item.path().to_str().unwrap().to_owned();
-        if metadata.is_file() && !is_ignored(&path.clone()) {
+        if metadata.is_file() && !is_ignored(&path) {
             let content = fs::read(path.clone()).unwrap();
             result.insert(
                 relative_path.to_str().unwrap().to_owned(),
@@ -456,7 +1471,12 @@ pub fn get_working_tree() -> HashMap<String, String> {
             );
         }
     }
-    return result;
+    result
+}
+
+pub fn get_commit_tree(name: String) -> HashMap<String, String> {
+    let commit = get_commit(get_oid(name));
+    get_tree(commit.tree, "".to_owned())
 }
 
 pub fn is_ancestor_of(commit: String, maybe_ancestor: String) -> bool {
@@ -467,33 +1487,65 @@ pub fn is_ancestor_of(commit: String, maybe_ancestor: String) -> bool {
             return true;
         }
     }
-    return false;
+    false
 }
 
 pub fn add(files: Vec<&str>) {
     let mut index = data::get_index();
-    for name in files {
+    let mut conflicts = data::get_index_conflicts();
+    for name in files.iter() {
+        // Entries that are no longer files in the working tree are staged as
+        // removed, like after accepting a deletion
+        let pathspec = vec![name.to_string()];
+        let tracked = diff::filter_tree(index.clone(), &pathspec);
+        for path in tracked.keys() {
+            if !Path::new(path).is_file() {
+                index.remove(path);
+            }
+        }
+
         let file_path = Path::new(name);
+        if !file_path.exists() {
+            let path = name.trim_start_matches("./");
+            assert!(
+                !tracked.is_empty() || conflicts.contains_key(path),
+                "Unknown path {}",
+                name
+            );
+            continue;
+        }
+
         let metadata = file_path.metadata().unwrap();
         if metadata.is_file() {
-            add_file(name.to_owned(), &mut index);
+            add_file(name.to_string(), &mut index);
         } else if metadata.is_dir() {
-            add_dir(name.to_owned(), &mut index);
+            add_dir(name.to_string(), &mut index);
         }
     }
+    // Adding a conflicted path marks it as resolved
+    let pathspecs: Vec<String> = files.iter().map(|name| name.to_string()).collect();
+    let resolved = diff::filter_tree(
+        conflicts
+            .keys()
+            .map(|path| (path.clone(), "".to_owned()))
+            .collect(),
+        &pathspecs,
+    );
+    conflicts.retain(|path, _| !resolved.contains_key(path));
     data::set_index(index);
+    data::set_index_conflicts(conflicts);
 }
 
-fn add_file(file: String, mut index: &mut HashMap<String, String>) {
+fn add_file(file: String, index: &mut HashMap<String, String>) {
     let content = fs::read(file.clone()).expect("Failed to read file");
     let hash = data::hash_object(&content, "blob".to_owned());
-    index.insert(file, hash);
+    index.insert(file.trim_start_matches("./").to_owned(), hash);
 }
 
-fn add_dir(dir: String, mut index: &mut HashMap<String, String>) {
+fn add_dir(dir: String, index: &mut HashMap<String, String>) {
     for entry in WalkDir::new(dir) {
         let item = entry.unwrap();
-        let relative_path = item.path().strip_prefix("./").unwrap();
+        let relative_path = item.path().strip_prefix("./").unwrap_or(item.path());
         let metadata = item.metadata().unwrap();
         let path = item.path().to_str().unwrap().to_owned();
         if metadata.is_file() && !is_ignored(&path) {
@@ -502,12 +1554,63 @@ fn add_dir(dir: String, mut index: &mut HashMap<String, String>) {
     }
 }
 
+pub fn update_working_files(
+    old_files: &HashMap<String, String>,
+    new_files: &HashMap<String, String>,
+) {
+    // Goes from one set of tracked files to another, without touching any
+    // other file in the working directory
+    for path in old_files.keys() {
+        if !new_files.contains_key(path) {
+            remove_working_file(path);
+        }
+    }
+    for (path, object_id) in new_files.iter() {
+        let mut dirs = Path::new(path).ancestors();
+        dirs.next();
+
+        let dir = dirs.next().unwrap().to_str().unwrap();
+        if !dir.is_empty() {
+            fs::create_dir_all(dir).expect("Cannot create required dirs");
+        }
+        fs::write(path, data::get_object(object_id.clone(), "".to_owned()))
+            .expect("Cannot write required object");
+    }
+}
+
+pub fn restore_tree(oid: String) {
+    // Like read_tree, but only the tracked paths are replaced, so untracked
+    // files are left alone
+    let mut old_files = data::get_index();
+    for path in data::get_index_conflicts().keys() {
+        old_files.insert(path.clone(), "".to_owned());
+    }
+    let new_files = get_tree(oid, "".to_owned());
+    update_working_files(&old_files, &new_files);
+    data::set_index(new_files);
+    data::set_index_conflicts(HashMap::new());
+}
+
+fn remove_working_file(path: &str) {
+    if fs::remove_file(path).is_err() {
+        return;
+    }
+    // Clean up the directories that were left empty
+    let mut dirs = Path::new(path).ancestors();
+    dirs.next();
+    for dir in dirs {
+        if dir.to_str() == Some("") || fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+}
+
 fn empty_current_directory(dir: &str) -> io::Result<()> {
     // Delete current directory, except the ignored directories and files
     for entry in fs::read_dir(dir)? {
         let entry = entry?;
         let path = entry.path();
-        if is_ignored(&path.to_str().unwrap().to_owned()) {
+        if is_ignored(path.to_str().unwrap()) {
             continue;
         }
 
@@ -525,21 +1628,236 @@ fn empty_current_directory(dir: &str) -> io::Result<()> {
 }
 
 fn is_branch(name: String) -> bool {
-    return data::get_ref(format!("refs/heads/{}", name), true).value != "";
+    !data::get_ref(format!("refs/heads/{}", name), true)
+        .value
+        .is_empty()
 }
 
-fn read_tree_merged(base_tree: String, head_tree: String, commit_tree: String) {
-    empty_current_directory(".").unwrap();
+pub fn read_tree_merged(
+    base_tree: String,
+    head_tree: String,
+    commit_tree: String,
+    favor: &str,
+) -> Vec<(String, String)> {
     let base_tree = get_tree(base_tree, "".to_owned());
     let head_tree = get_tree(head_tree, "".to_owned());
     let commit_tree = get_tree(commit_tree, "".to_owned());
-    for (path, blob) in diff::merge_trees(base_tree, head_tree, commit_tree) {
-        let mut dirs = Path::new(&path).ancestors();
+    // Untracked files are left alone
+    for path in head_tree.keys() {
+        remove_working_file(path);
+    }
+    let mut style = data::get_config_value("merge.conflictstyle");
+    if style.is_empty() {
+        style = "merge".to_owned();
+    }
+
+    let mut index = HashMap::new();
+    let mut conflicts = HashMap::new();
+    let mut messages = vec![];
+    let merged = diff::merge_trees(
+        base_tree.clone(),
+        head_tree.clone(),
+        commit_tree.clone(),
+        &style,
+        favor,
+    );
+    for (path, blob) in merged {
+        let mut dirs = Path::new(&blob.path).ancestors();
         dirs.next();
 
         let dir = dirs.next().unwrap().to_str().unwrap();
 
         fs::create_dir_all(dir).expect("Cannot create required dirs");
-        fs::write(path, blob).expect("Cannot write required object");
+        if !blob.conflict.is_empty() {
+            // Keep every version around in the index until it is resolved
+            let stages = vec![
+                base_tree.get(&path).cloned().unwrap_or("".to_owned()),
+                head_tree.get(&path).cloned().unwrap_or("".to_owned()),
+                commit_tree.get(&path).cloned().unwrap_or("".to_owned()),
+            ];
+            conflicts.insert(path.clone(), stages);
+            messages.push((path.clone(), conflict_message(&path, &blob)));
+        } else {
+            index.insert(
+                path.clone(),
+                data::hash_object(&blob.content.clone().into_bytes(), "blob".to_owned()),
+            );
+        }
+        fs::write(blob.path, blob.content).expect("Cannot write required object");
+    }
+    data::set_index(index);
+    data::set_index_conflicts(conflicts);
+
+    messages.sort();
+    messages
+}
+
+fn conflict_message(path: &String, blob: &diff::MergedFile) -> String {
+    let details = match blob.conflict.as_str() {
+        "modify/delete" => format!("{} deleted on one side and modified on the other", path),
+        "file/directory" => format!(
+            "{} is a directory on the other side, file left in tree as {}",
+            path, blob.path
+        ),
+        _ => format!("Merge conflict in {}", path),
+    };
+    format!("CONFLICT ({}): {}", blob.conflict, details)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::with_repo;
+    use std::panic;
+
+    fn make_commit(files: &[(&str, &str)], parents: &[&str], message: &str) -> String {
+        let files: HashMap<String, String> = files
+            .iter()
+            .map(|(path, content)| {
+                let oid = data::hash_object(content.as_bytes(), "blob".to_owned());
+                (path.to_string(), oid)
+            })
+            .collect();
+        let mut commit = format!("tree {}\n", write_tree_from(&files));
+        for parent in parents {
+            commit += format!("parent {}\n", parent).as_str();
+        }
+        commit += format!("\n{}\n", message).as_str();
+        data::hash_object(&commit.into_bytes(), "commit".to_owned())
+    }
+
+    fn criss_cross() -> (String, String, String, String) {
+        //   root - a1 - a2 - a3
+        //       \     X
+        //        b1 - b2 - b3
+        // a2 and b2 both merge a1 and b1, so a3 and b3 have two merge bases.
+        let root = make_commit(&[("a", "0\n"), ("b", "0\n")], &[], "root");
+        let a1 = make_commit(&[("a", "1\n"), ("b", "0\n")], &[&root], "a1");
+        let b1 = make_commit(&[("a", "0\n"), ("b", "1\n")], &[&root], "b1");
+        let a2 = make_commit(&[("a", "1\n"), ("b", "1\n")], &[&a1, &b1], "a2");
+        let b2 = make_commit(&[("a", "1\n"), ("b", "1\n")], &[&b1, &a1], "b2");
+        let a3 = make_commit(&[("a", "2\n"), ("b", "1\n")], &[&a2], "a3");
+        let b3 = make_commit(&[("a", "1\n"), ("b", "2\n")], &[&b2], "b3");
+        (a1, b1, a3, b3)
+    }
+
+    fn list_objects() -> HashSet<String> {
+        fs::read_dir(".rgit/objects")
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn criss_cross_has_every_best_merge_base() {
+        with_repo(|| {
+            let (a1, b1, a3, b3) = criss_cross();
+            let mut bases = get_merge_bases(a3.clone(), b3.clone());
+            bases.sort();
+            let mut expected = vec![a1, b1];
+            expected.sort();
+            assert_eq!(bases, expected);
+            assert!(expected.contains(&get_merge_base(a3, b3)));
+        });
+    }
+
+    #[test]
+    fn recursive_strategy_merges_the_bases() {
+        with_repo(|| {
+            let (_, _, a3, b3) = criss_cross();
+            let a3_tree = get_commit(a3.clone()).tree;
+            let b3_tree = get_commit(b3.clone()).tree;
+
+            // The virtual base has both sides of the criss-cross, so each
+            // side of the final merge only changed one file
+            let before = list_objects();
+            let base_tree = get_merge_base_tree(a3.clone(), b3.clone(), "recursive");
+            let base = get_tree(base_tree.clone(), "".to_owned());
+            assert_eq!(
+                data::get_object(base["a"].clone(), "blob".to_owned()),
+                "1\n"
+            );
+            assert_eq!(
+                data::get_object(base["b"].clone(), "blob".to_owned()),
+                "1\n"
+            );
+            let (tree, conflicts) =
+                merge_tree_objects(base_tree, a3_tree.clone(), b3_tree.clone(), "");
+            assert!(conflicts.is_empty());
+            let merged = get_tree(tree, "".to_owned());
+            assert_eq!(
+                data::get_object(merged["a"].clone(), "blob".to_owned()),
+                "2\n"
+            );
+            assert_eq!(
+                data::get_object(merged["b"].clone(), "blob".to_owned()),
+                "2\n"
+            );
+
+            // No commit is written for the virtual base
+            for oid in list_objects().difference(&before) {
+                assert_ne!(data::get_object_type(oid), "commit");
+            }
+
+            // A single base conflicts on the file the other base changed
+            let base_tree = get_merge_base_tree(a3, b3, "resolve");
+            let (_, conflicts) = merge_tree_objects(base_tree, a3_tree, b3_tree, "");
+            assert_eq!(conflicts.len(), 1);
+        });
+    }
+
+    #[test]
+    fn restore_tree_keeps_untracked_files() {
+        with_repo(|| {
+            let tree = write_tree_from(&HashMap::from([(
+                "tracked".to_owned(),
+                data::hash_object(b"1\n", "blob".to_owned()),
+            )]));
+            fs::write("tracked", "2\n").unwrap();
+            fs::write("added", "1\n").unwrap();
+            add(vec!["tracked", "added"]);
+            fs::write("untracked", "1\n").unwrap();
+
+            restore_tree(tree);
+            assert_eq!(fs::read_to_string("tracked").unwrap(), "1\n");
+            assert!(!Path::new("added").exists());
+            assert!(Path::new("untracked").exists());
+            assert_eq!(data::get_index().len(), 1);
+        });
+    }
+
+    #[test]
+    fn upstream_must_be_a_branch() {
+        with_repo(|| {
+            let oid = make_commit(&[("a", "1\n")], &[], "root");
+            create_branch("master".to_owned(), oid.clone(), false);
+            create_branch("other".to_owned(), oid.clone(), false);
+            create_tag("v1".to_owned(), oid);
+
+            set_upstream("master", "other");
+            assert_eq!(get_upstream("master"), "refs/heads/other");
+            let result = panic::catch_unwind(|| set_upstream("master", "v1"));
+            assert!(result.is_err());
+            assert_eq!(get_upstream("master"), "refs/heads/other");
+        });
+    }
+
+    #[test]
+    fn commit_records_the_index() {
+        with_repo(|| {
+            fs::write("staged", "1\n").unwrap();
+            add(vec!["staged"]);
+            // Neither the later change nor the untracked file are staged
+            fs::write("staged", "2\n").unwrap();
+            fs::write("untracked", "1\n").unwrap();
+
+            let oid = commit("first", false);
+            let tree = get_tree(get_commit(oid).tree, "".to_owned());
+            assert_eq!(tree.len(), 1);
+            assert_eq!(
+                data::get_object(tree["staged"].clone(), "blob".to_owned()),
+                "1\n"
+            );
+        });
     }
 }
@@ -0,0 +1,160 @@
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+#[path = "data.rs"]
+#[allow(dead_code)]
+mod data;
+
+static RESET: &str = "\u{1b}[m";
+
+// Default color of each slot. Any of them can be replaced through the
+// "color.<slot>" config entry, using the same syntax as Git (ex: "bold red").
+static DEFAULT_PALETTE: [(&str, &str); 15] = [
+    ("diff.meta", "bold"),
+    ("diff.frag", "cyan"),
+    ("diff.func", "normal"),
+    ("diff.old", "red"),
+    ("diff.new", "green"),
+    ("diff.commit", "yellow"),
+    ("decorate.HEAD", "bold cyan"),
+    ("decorate.branch", "bold green"),
+    ("decorate.remoteBranch", "bold red"),
+    ("decorate.tag", "bold yellow"),
+    ("status.header", "normal"),
+    ("status.added", "green"),
+    ("status.changed", "red"),
+    ("status.unmerged", "red"),
+    ("status.nobranch", "red"),
+];
+
+lazy_static! {
+    static ref PALETTE: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+}
+
+pub fn setup(mode: &str) {
+    // Decides if the output should be colored and loads the palette.
+    // "auto" only colors the output when it goes to a terminal.
+    let mut mode = mode.to_owned();
+    if mode.is_empty() {
+        mode = data::get_config_value("color.ui");
+    }
+    let enabled = match mode.as_str() {
+        "always" | "true" => true,
+        "never" | "false" => false,
+        _ => unsafe { libc::isatty(libc::STDOUT_FILENO) == 1 },
+    };
+
+    let mut palette = PALETTE.lock().unwrap();
+    palette.clear();
+    if !enabled {
+        return;
+    }
+
+    let config = data::get_config();
+    for (slot, default) in DEFAULT_PALETTE.iter() {
+        let spec = match config.get(&format!("color.{}", slot)) {
+            Some(value) => value.clone(),
+            None => default.to_string(),
+        };
+        palette.insert(slot.to_string(), parse_color(&spec));
+    }
+}
+
+pub fn paint(slot: &str, text: &str) -> String {
+    let palette = PALETTE.lock().unwrap();
+    match palette.get(slot) {
+        Some(code) if !code.is_empty() => format!("{}{}{}", code, text, RESET),
+        _ => text.to_owned(),
+    }
+}
+
+pub fn diff(text: &str) -> String {
+    let mut output = String::new();
+    for line in text.split_inclusive("\n") {
+        let content = line.trim_end_matches("\n");
+        let colored = if content.starts_with("---") || content.starts_with("+++") {
+            paint("diff.meta", content)
+        } else if let Some(rest) = content.strip_prefix("@@") {
+            // Hunk header, the function name that follows it has its own color
+            let end = rest.find("@@").map(|i| i + 4).unwrap_or(content.len());
+            format!(
+                "{}{}",
+                paint("diff.frag", &content[..end]),
+                paint("diff.func", &content[end..])
+            )
+        } else if content.starts_with("+") {
+            paint("diff.new", content)
+        } else if content.starts_with("-") {
+            paint("diff.old", content)
+        } else {
+            content.to_owned()
+        };
+        output.push_str(&colored);
+        if line.ends_with("\n") {
+            output.push('\n');
+        }
+    }
+    output
+}
+
+pub fn decoration(refname: &str) -> String {
+    let slot = if refname == "HEAD" {
+        "decorate.HEAD"
+    } else if refname.starts_with("refs/tags/") {
+        "decorate.tag"
+    } else if refname.starts_with("refs/remotes/") {
+        "decorate.remoteBranch"
+    } else {
+        "decorate.branch"
+    };
+    paint(slot, refname)
+}
+
+fn parse_color(spec: &str) -> String {
+    // Translates a color description into the matching ANSI escape sequence
+    let names = [
+        "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+    ];
+    let mut codes: Vec<String> = vec![];
+    let mut colors_seen = 0;
+
+    for word in spec.split_whitespace() {
+        let attribute = match word {
+            "bold" => "1",
+            "dim" => "2",
+            "italic" => "3",
+            "ul" => "4",
+            "blink" => "5",
+            "reverse" => "7",
+            _ => "",
+        };
+        if !attribute.is_empty() {
+            codes.push(attribute.to_owned());
+            continue;
+        }
+
+        // The first color is the foreground, the second is the background
+        let base = if colors_seen == 0 { 30 } else { 40 };
+        colors_seen += 1;
+        if word == "normal" || word == "default" {
+            continue;
+        } else if let Some(i) = names.iter().position(|n| *n == word) {
+            codes.push(format!("{}", base + i));
+        } else if let Some(i) = names
+            .iter()
+            .position(|n| word.strip_prefix("bright") == Some(n))
+        {
+            codes.push(format!("{}", base + 60 + i));
+        } else if let Ok(number) = word.parse::<u8>() {
+            codes.push(format!("{};5;{}", base + 8, number));
+        } else {
+            panic!("Invalid color: {}", word);
+        }
+    }
+
+    if codes.is_empty() {
+        return "".to_owned();
+    }
+    format!("\u{1b}[{}m", codes.join(";"))
+}
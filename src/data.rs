@@ -1,15 +1,17 @@
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
-use serde_json::Result;
 use sha1::{Digest, Sha1};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::env;
 use std::fs;
+use std::io::Write;
 use std::path::Path;
 use std::str;
 use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
 use walkdir::WalkDir;
 
-static BASE_RGIT_DIR: &'static str = ".rgit";
+static BASE_RGIT_DIR: &str = ".rgit";
 
 lazy_static! {
     static ref RGIT_DIR: Mutex<String> = Mutex::new(BASE_RGIT_DIR.to_owned());
@@ -21,9 +23,26 @@ pub struct RefValue {
     pub symbolic: bool,
 }
 
+pub struct ReflogEntry {
+    pub old: String,
+    pub new: String,
+    pub identity: String,
+    pub timestamp: i64,
+    pub timezone: String,
+    pub message: String,
+}
+
 #[derive(Serialize, Deserialize)]
 struct Index {
     files: HashMap<String, String>,
+    // Unmerged paths, with the oids of stages 1 (base), 2 (ours) and 3 (theirs)
+    #[serde(default)]
+    conflicts: HashMap<String, Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Config {
+    values: HashMap<String, String>,
 }
 
 // The below two methods are not the same thing as a "context manager"
@@ -54,11 +73,10 @@ pub fn init() -> std::io::Result<()> {
     Ok(())
 }
 
-pub fn hash_object(content: &Vec<u8>, _type: String) -> String {
+pub fn hash_object(content: &[u8], _type: String) -> String {
     let dir = RGIT_DIR.lock().unwrap().to_owned();
     let mut raw = format!("{}\u{0}", _type).into_bytes();
-    let mut data = content.clone();
-    raw.append(&mut data);
+    raw.extend_from_slice(content);
 
     let mut hasher = Sha1::new();
     hasher.update(&raw);
@@ -68,7 +86,7 @@ pub fn hash_object(content: &Vec<u8>, _type: String) -> String {
     fs::write(format!("{}/{}/{}", dir, "objects", s), raw.as_slice())
         .expect("Failed to write object");
 
-    return s;
+    s
 }
 
 pub fn get_object(hash: String, expected: String) -> String {
@@ -79,44 +97,412 @@ pub fn get_object(hash: String, expected: String) -> String {
     let index = content.find(char::from(0)).expect("object type missing");
     let data = content.split_off(index + 1);
 
-    if expected != "".to_owned() {
+    if !expected.is_empty() {
         // Compare the type
         content.pop();
         assert_eq!(expected, content);
     }
 
-    return data;
+    data
 }
 
-pub fn update_ref(mut reference: String, value: RefValue, deref: bool) {
+pub fn get_object_type(hash: &str) -> String {
     let dir = RGIT_DIR.lock().unwrap().to_owned();
-    reference = get_ref_internal(reference, deref).0;
-    let content: String;
+    let content = fs::read(format!("{}/{}/{}", dir, "objects", hash))
+        .expect("Could not find a matching object");
+    let end = content
+        .iter()
+        .position(|b| *b == 0)
+        .expect("object type missing");
+    String::from_utf8_lossy(&content[..end]).to_string()
+}
 
-    assert!(value.value != "");
-    if value.symbolic {
-        content = format!("ref: {}", value.value);
-    } else {
-        content = value.value;
+pub fn find_objects(prefix: &str) -> Vec<String> {
+    // Oids of all the objects starting with the given prefix
+    let dir = RGIT_DIR.lock().unwrap().to_owned();
+    let mut oids = vec![];
+    for entry in fs::read_dir(format!("{}/objects", dir)).expect("Cannot read the objects") {
+        let name = entry.unwrap().file_name().to_str().unwrap().to_owned();
+        if name.starts_with(prefix) {
+            oids.push(name);
+        }
+    }
+    oids
+}
+
+pub fn update_ref(reference: String, value: RefValue, deref: bool, message: &str) {
+    let mut transaction = RefTransaction::new();
+    transaction.update(reference, value, deref, None, message);
+    transaction.commit();
+}
+
+pub fn get_ref(reference: String, deref: bool) -> RefValue {
+    get_ref_internal(reference, deref).1
+}
+
+pub fn delete_ref(reference: String, deref: bool) {
+    let mut transaction = RefTransaction::new();
+    transaction.delete(reference, deref, None);
+    transaction.commit();
+}
+
+// Refs are changed by writing the new value to "<ref>.lock" and renaming it
+// into place. The lock file also keeps other rgit processes away from the ref
+// while it is being changed. A transaction takes the locks of all its refs
+// before touching any of them, so either every update goes through or none.
+pub struct RefTransaction {
+    updates: Vec<RefUpdate>,
+}
+
+struct RefUpdate {
+    reference: String,
+    // None deletes the ref
+    value: Option<RefValue>,
+    deref: bool,
+    // Value the ref must have for the update to go through ("" if it must not
+    // exist yet), or None to update it no matter what
+    expected: Option<String>,
+    message: String,
+}
+
+impl RefTransaction {
+    pub fn new() -> RefTransaction {
+        RefTransaction { updates: vec![] }
     }
 
-    let path = format!("{}/{}", dir, reference);
+    pub fn update(
+        &mut self,
+        reference: String,
+        value: RefValue,
+        deref: bool,
+        expected: Option<String>,
+        message: &str,
+    ) {
+        assert!(!value.value.is_empty());
+        self.updates.push(RefUpdate {
+            reference,
+            value: Some(value),
+            deref,
+            expected,
+            message: message.to_owned(),
+        });
+    }
+
+    pub fn delete(&mut self, reference: String, deref: bool, expected: Option<String>) {
+        self.updates.push(RefUpdate {
+            reference,
+            value: None,
+            deref,
+            expected,
+            message: "".to_owned(),
+        });
+    }
+
+    pub fn commit(self) {
+        let dir = RGIT_DIR.lock().unwrap().to_owned();
+        let mut locked = Locks(vec![]);
+        let mut targets: Vec<(String, String)> = vec![];
+
+        for update in self.updates.iter() {
+            let target = get_ref_internal(update.reference.clone(), update.deref).0;
+            assert!(
+                !targets.iter().any(|(other, _)| *other == target),
+                "Cannot update {} twice in the same transaction",
+                target
+            );
+            let path = format!("{}/{}", dir, target);
+            lock_file(&path, &mut locked);
+            targets.push((target, path));
+        }
+
+        // Deleted refs also have to go away from packed-refs, which is locked
+        // before being read so a concurrent pack-refs is not undone
+        let packed_path = format!("{}/packed-refs", dir);
+        let deleting = self.updates.iter().any(|update| update.value.is_none());
+        if deleting {
+            lock_file(&packed_path, &mut locked);
+        }
+        let mut packed_refs = read_packed_refs();
+        let packed_count = packed_refs.len();
+        for (i, update) in self.updates.iter().enumerate() {
+            if update.value.is_none() {
+                packed_refs.remove(&targets[i].0);
+            }
+        }
+
+        // Only check the values once nobody else can change them
+        let mut old_values = vec![];
+        for update in self.updates.iter() {
+            let current = get_ref_internal(update.reference.clone(), true).1.value;
+            if let Some(expected) = &update.expected {
+                if *expected != current {
+                    panic!(
+                        "Cannot update {}: expected it at {:?} but it is at {:?}",
+                        update.reference, expected, current
+                    );
+                }
+            }
+            old_values.push(current);
+        }
+
+        if packed_refs.len() != packed_count {
+            write_packed_refs(&packed_path, &packed_refs, &mut locked);
+        } else if deleting {
+            locked.release(&packed_path);
+        }
+        for (i, update) in self.updates.iter().enumerate() {
+            let (target, path) = &targets[i];
+            let lock_path = format!("{}.lock", path);
+            match &update.value {
+                Some(value) => {
+                    let content = if value.symbolic {
+                        format!("ref: {}", value.value)
+                    } else {
+                        value.value.clone()
+                    };
+                    fs::write(&lock_path, content).expect("Failed to write the ref");
+                    fs::rename(&lock_path, path).expect("Failed to update the ref");
+                    locked.forget(path);
+                }
+                None => {
+                    if Path::new(path).exists() {
+                        fs::remove_file(path).expect("Failed to delete the ref");
+                    }
+                    locked.release(path);
+                    // Leave no empty directories behind (ex: refs/remotes/<name>/),
+                    // but keep the top ones like refs/heads/
+                    let mut parents = Path::new(path).ancestors();
+                    parents.next();
+                    for parent in parents {
+                        let top = parent.parent().is_none_or(|dir| dir.ends_with("refs"));
+                        if top || fs::remove_dir(parent).is_err() {
+                            break;
+                        }
+                    }
+                    if target != "HEAD" {
+                        remove_rgit_file(&format!("logs/{}", target));
+                    }
+                    continue;
+                }
+            }
+
+            // Updating a branch through HEAD is recorded in both logs
+            let new_value = get_ref_internal(target.clone(), true).1.value;
+            append_reflog(target, &old_values[i], &new_value, &update.message);
+            if update.reference != *target {
+                append_reflog(
+                    &update.reference,
+                    &old_values[i],
+                    &new_value,
+                    &update.message,
+                );
+            }
+        }
+    }
+}
+
+pub fn pack_refs(all: bool) {
+    // Moves the loose refs into packed-refs, only the tags unless "all" is set.
+    // Symbolic refs are always left as they are.
+    let dir = RGIT_DIR.lock().unwrap().to_owned();
+    let packed_path = format!("{}/packed-refs", dir);
+    let mut locked = Locks(vec![]);
+    lock_file(&packed_path, &mut locked);
+
+    let mut packed_refs = read_packed_refs();
+    let mut loose_refs = vec![];
+    for (name, value) in iter_loose_refs(&dir) {
+        if value.symbolic || !(all || name.starts_with("refs/tags/")) {
+            continue;
+        }
+        packed_refs.insert(name.clone(), value.value.clone());
+        loose_refs.push((name, value.value));
+    }
+    write_packed_refs(&packed_path, &packed_refs, &mut locked);
+
+    for (name, value) in loose_refs {
+        // Keep the loose ref if it changed in the meantime
+        let path = format!("{}/{}", dir, name);
+        let mut locked = Locks(vec![]);
+        lock_file(&path, &mut locked);
+        if fs::read_to_string(&path).unwrap_or("".to_owned()) == value {
+            fs::remove_file(&path).expect("Failed to remove the loose ref");
+        }
+    }
+}
+
+fn read_packed_refs() -> HashMap<String, String> {
+    // Lines are "<oid> <ref>", comments start with "#"
+    let mut refs = HashMap::new();
+    for line in read_rgit_file("packed-refs").lines() {
+        if line.starts_with("#") || line.starts_with("^") {
+            continue;
+        }
+        let items: Vec<&str> = line.splitn(2, " ").collect();
+        if items.len() == 2 {
+            refs.insert(items[1].to_owned(), items[0].to_owned());
+        }
+    }
+    refs
+}
+
+fn write_packed_refs(path: &str, refs: &HashMap<String, String>, locked: &mut Locks) {
+    // Expects packed-refs to be locked, the lock is used up once done
+    let mut names: Vec<&String> = refs.keys().collect();
+    names.sort();
+    let mut content = "# pack-refs with: sorted\n".to_owned();
+    for name in names {
+        content += format!("{} {}\n", refs[name], name).as_str();
+    }
+    let lock_path = format!("{}.lock", path);
+    fs::write(&lock_path, content).expect("Failed to write packed-refs");
+    fs::rename(&lock_path, path).expect("Failed to update packed-refs");
+    locked.forget(path);
+}
+
+// The lock files taken so far. Whatever is still held when this goes away
+// (including when a panic unwinds through the caller) is removed, so a failed
+// update never leaves stale locks behind.
+struct Locks(Vec<String>);
+
+impl Locks {
+    fn release(&mut self, path: &str) {
+        let _ = fs::remove_file(format!("{}.lock", path));
+        self.forget(path);
+    }
+
+    fn forget(&mut self, path: &str) {
+        // The lock file was renamed over the file it protects
+        self.0.retain(|locked| locked != path);
+    }
+}
+
+impl Drop for Locks {
+    fn drop(&mut self) {
+        for path in self.0.iter() {
+            let _ = fs::remove_file(format!("{}.lock", path));
+        }
+    }
+}
+
+fn lock_file(path: &str, locked: &mut Locks) {
+    // Creates "<path>.lock", giving up if it exists
+    let mut parents = Path::new(path).ancestors();
+    parents.next();
+
+    let parent = parents.next().unwrap().to_str().unwrap();
+    fs::create_dir_all(parent)
+        .unwrap_or_else(|error| panic!("Cannot create {} for {}: {}", parent, path, error));
+    let lock = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(format!("{}.lock", path));
+    if lock.is_err() {
+        panic!(
+            "Unable to create {}.lock: another rgit process seems to be running. \
+             If not, remove the file and try again",
+            path
+        );
+    }
+    locked.0.push(path.to_owned());
+}
+
+// Every change to HEAD and to the branches is appended to .rgit/logs/<ref>,
+// one "<old> <new> <identity> <timestamp> <timezone>\t<message>" per line
+pub fn read_reflog(reference: &str) -> Vec<ReflogEntry> {
+    let mut entries = vec![];
+    for line in read_rgit_file(&format!("logs/{}", reference)).lines() {
+        let items: Vec<&str> = line.splitn(2, "\t").collect();
+        let header: Vec<&str> = items[0].splitn(3, " ").collect();
+        let details: Vec<&str> = header[2].rsplitn(3, " ").collect();
+        entries.push(ReflogEntry {
+            old: header[0].to_owned(),
+            new: header[1].to_owned(),
+            identity: details[2].to_owned(),
+            timestamp: details[1].parse().unwrap_or(0),
+            timezone: details[0].to_owned(),
+            message: items.get(1).unwrap_or(&"").to_string(),
+        });
+    }
+    entries
+}
+
+pub fn write_reflog(reference: &str, entries: &Vec<ReflogEntry>) {
+    let mut content = "".to_owned();
+    for entry in entries {
+        content += format!(
+            "{} {} {} {} {}\t{}\n",
+            entry.old, entry.new, entry.identity, entry.timestamp, entry.timezone, entry.message
+        )
+        .as_str();
+    }
+    write_rgit_file(&format!("logs/{}", reference), &content);
+}
+
+fn append_reflog(reference: &str, old_value: &str, new_value: &str, message: &str) {
+    let logged = reference == "HEAD"
+        || reference == "refs/stash"
+        || reference.starts_with("refs/heads/")
+        || reference.starts_with("refs/remotes/");
+    if !logged || new_value.is_empty() {
+        return;
+    }
+
+    let (timestamp, timezone) = get_timestamp();
+    let old = if !old_value.is_empty() {
+        old_value.to_owned()
+    } else {
+        "0".repeat(40)
+    };
+    let line = format!(
+        "{} {} {} {} {}\t{}\n",
+        old,
+        new_value,
+        get_identity(),
+        timestamp,
+        timezone,
+        message
+    );
+
+    let path = get_rgit_file_path(&format!("logs/{}", reference));
     let mut parents = Path::new(&path).ancestors();
     parents.next();
 
     let parent = parents.next().unwrap().to_str().unwrap();
     fs::create_dir_all(parent).expect("Cannot create required dirs");
-    fs::write(path, content).expect("Failed to updated HEAD");
+    let mut log = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .expect("Failed to open the reflog");
+    log.write_all(line.as_bytes())
+        .expect("Failed to write the reflog");
 }
 
-pub fn get_ref(reference: String, deref: bool) -> RefValue {
-    return get_ref_internal(reference, deref).1;
+pub fn get_identity() -> String {
+    // "Name <email>", from the user.name and user.email config entries
+    let mut name = get_config_value("user.name");
+    if name.is_empty() {
+        name = env::var("USER").unwrap_or("unknown".to_owned());
+    }
+    format!("{} <{}>", name, get_config_value("user.email"))
 }
 
-pub fn delete_ref(reference: String, deref: bool) {
-    let dir = RGIT_DIR.lock().unwrap().to_owned();
-    let ref_to_del = get_ref_internal(reference, deref).0;
-    fs::remove_file(format!("{}/{}", dir, ref_to_del)).unwrap();
+pub fn get_timestamp() -> (i64, String) {
+    // Seconds since the epoch and the local timezone (ex: "+0100")
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let offset = unsafe {
+        let mut tm: libc::tm = std::mem::zeroed();
+        let time = now as libc::time_t;
+        libc::localtime_r(&time, &mut tm);
+        tm.tm_gmtoff / 60
+    };
+    let sign = if offset < 0 { "-" } else { "+" };
+    let timezone = format!("{}{:02}{:02}", sign, offset.abs() / 60, offset.abs() % 60);
+    (now, timezone)
 }
 
 pub fn iter_refs(prefix: &str, deref: bool) -> Vec<(String, RefValue)> {
@@ -124,37 +510,83 @@ pub fn iter_refs(prefix: &str, deref: bool) -> Vec<(String, RefValue)> {
     let mut refs: Vec<(String, RefValue)> = vec![];
 
     refs.push(("HEAD".to_owned(), get_ref("HEAD".to_owned(), deref)));
-    refs.push((
-        "MERGE_HEAD".to_owned(),
-        get_ref("MERGE_HEAD".to_owned(), deref),
-    ));
-
-    for entry in WalkDir::new(format!("{}/refs/", dir.clone())) {
-        let item = entry.unwrap();
-        let metadata = item.metadata().unwrap();
+    // An octopus merge has one commit per line
+    for oid in get_ref("MERGE_HEAD".to_owned(), deref).value.lines() {
+        refs.push((
+            "MERGE_HEAD".to_owned(),
+            RefValue {
+                value: oid.to_owned(),
+                symbolic: false,
+            },
+        ));
+    }
 
-        if metadata.is_file() {
-            let relative_path = item.path().strip_prefix(dir.clone()).unwrap();
+    // Loose refs take precedence over the packed ones
+    let mut names = HashSet::new();
+    for (name, value) in iter_loose_refs(&dir) {
+        let value = if deref {
+            get_ref(name.clone(), deref)
+        } else {
+            value
+        };
+        names.insert(name.clone());
+        refs.push((name, value));
+    }
+    let mut packed_refs: Vec<(String, String)> = read_packed_refs().into_iter().collect();
+    packed_refs.sort();
+    for (name, value) in packed_refs {
+        if !names.contains(&name) {
             refs.push((
-                relative_path.to_str().unwrap().to_owned(),
-                get_ref(relative_path.to_str().unwrap().to_owned(), deref),
+                name,
+                RefValue {
+                    value,
+                    symbolic: false,
+                },
             ));
         }
     }
 
     let mut filtered_refs = vec![];
     for reference in refs {
-        if reference.0.starts_with(prefix) && reference.1.value != "".to_owned() {
+        if reference.0.starts_with(prefix) && !reference.1.value.is_empty() {
             filtered_refs.push(reference);
         }
     }
-    return filtered_refs;
+    filtered_refs
+}
+
+fn iter_loose_refs(dir: &str) -> Vec<(String, RefValue)> {
+    let mut refs = vec![];
+    let refs_dir = format!("{}/refs/", dir);
+    if !Path::new(&refs_dir).exists() {
+        return refs;
+    }
+    for entry in WalkDir::new(refs_dir) {
+        let item = entry.unwrap();
+        let metadata = item.metadata().unwrap();
+
+        // Lock files are refs in the middle of being updated
+        let is_lock = item.path().extension() == Some("lock".as_ref());
+        if metadata.is_file() && !is_lock {
+            let relative_path = item.path().strip_prefix(dir).unwrap();
+            let name = relative_path.to_str().unwrap().to_owned();
+            refs.push((name.clone(), get_ref(name, false)));
+        }
+    }
+    refs
 }
 
 pub fn get_ref_internal(reference: String, deref: bool) -> (String, RefValue) {
     let dir = RGIT_DIR.lock().unwrap().to_owned();
     let ref_path = format!("{}/{}", dir, reference);
-    let mut value = fs::read_to_string(ref_path).unwrap_or("".to_owned());
+    let mut value = match fs::read_to_string(ref_path) {
+        Ok(value) => value,
+        Err(_) if reference.starts_with("refs/") => read_packed_refs()
+            .get(&reference)
+            .cloned()
+            .unwrap_or("".to_owned()),
+        Err(_) => "".to_owned(),
+    };
     let symbolic = !value.is_empty() && value.starts_with("ref:");
 
     if symbolic {
@@ -165,7 +597,7 @@ pub fn get_ref_internal(reference: String, deref: bool) -> (String, RefValue) {
         }
     }
 
-    return (reference, RefValue { value, symbolic });
+    (reference, RefValue { value, symbolic })
 }
 
 pub fn fetch_object_if_missing(oid: String, remote_git_dir: String) {
@@ -179,7 +611,7 @@ pub fn fetch_object_if_missing(oid: String, remote_git_dir: String) {
         format!("{}/objects/{}", rgit_remote, oid.clone()),
         format!("{}/objects/{}", dir, oid),
     )
-    .expect(format!("Failed to fetch {}", oid).as_str());
+    .unwrap_or_else(|_| panic!("Failed to fetch {}", oid));
 }
 
 pub fn push_object(oid: String, remote_git_dir: String) {
@@ -195,33 +627,33 @@ pub fn push_object(oid: String, remote_git_dir: String) {
 
     let dir = RGIT_DIR.lock().unwrap().to_owned();
     fs::copy(format!("{}/objects/{}", dir, oid), remote_object)
-        .expect(format!("Failed to push {}", oid).as_str());
+        .unwrap_or_else(|_| panic!("Failed to push {}", oid));
 }
 
 fn object_exists(oid: String) -> bool {
     let dir = RGIT_DIR.lock().unwrap().to_owned();
     let path = format!("{}/objects/{}", dir.clone(), oid.clone());
-    return Path::new(path.as_str()).exists();
+    Path::new(path.as_str()).exists()
 }
 
-pub fn get_index() -> HashMap<String, String> {
-    let mut index_files = HashMap::new();
+fn read_index() -> Index {
     let dir = RGIT_DIR.lock().unwrap().to_owned();
     let index_path = format!("{}/index", dir.clone());
     let path = Path::new(index_path.as_str());
 
     if path.exists() {
         let index_content = fs::read_to_string(path).expect("Failed to read index file");
-        let index: Index = serde_json::from_str(index_content.as_str()).unwrap();
-        index_files = index.files;
+        return serde_json::from_str(index_content.as_str()).unwrap();
     }
 
-    return index_files;
+    Index {
+        files: HashMap::new(),
+        conflicts: HashMap::new(),
+    }
 }
 
-pub fn set_index(files: HashMap<String, String>) {
-    let new_index = Index { files: files };
-    let index_content = serde_json::to_string(&new_index).expect("Failed to serialize index");
+fn write_index(index: Index) {
+    let index_content = serde_json::to_string(&index).expect("Failed to serialize index");
 
     let dir = RGIT_DIR.lock().unwrap().to_owned();
     let index_path = format!("{}/index", dir.clone());
@@ -229,3 +661,176 @@ pub fn set_index(files: HashMap<String, String>) {
 
     fs::write(path, index_content).expect("Failed to write index");
 }
+
+pub fn get_index() -> HashMap<String, String> {
+    read_index().files
+}
+
+pub fn set_index(files: HashMap<String, String>) {
+    let mut index = read_index();
+    index.files = files;
+    write_index(index);
+}
+
+pub fn get_index_conflicts() -> HashMap<String, Vec<String>> {
+    read_index().conflicts
+}
+
+pub fn set_index_conflicts(conflicts: HashMap<String, Vec<String>>) {
+    let mut index = read_index();
+    index.conflicts = conflicts;
+    write_index(index);
+}
+
+// Helpers for the plain files that keep the state of the commands that can be
+// interrupted (ex: MERGE_MSG)
+pub fn read_rgit_file(name: &str) -> String {
+    let dir = RGIT_DIR.lock().unwrap().to_owned();
+    fs::read_to_string(format!("{}/{}", dir, name)).unwrap_or("".to_owned())
+}
+
+pub fn write_rgit_file(name: &str, content: &str) {
+    let dir = RGIT_DIR.lock().unwrap().to_owned();
+    let path = format!("{}/{}", dir, name);
+    let mut parents = Path::new(&path).ancestors();
+    parents.next();
+
+    let parent = parents.next().unwrap().to_str().unwrap();
+    fs::create_dir_all(parent).expect("Cannot create required dirs");
+    fs::write(path, content).expect("Failed to write state file");
+}
+
+pub fn get_rgit_file_path(name: &str) -> String {
+    let dir = RGIT_DIR.lock().unwrap().to_owned();
+    format!("{}/{}", dir, name)
+}
+
+pub fn remove_rgit_file(name: &str) {
+    let dir = RGIT_DIR.lock().unwrap().to_owned();
+    let path = format!("{}/{}", dir, name);
+    if Path::new(&path).is_dir() {
+        fs::remove_dir_all(path).expect("Failed to remove state directory");
+    } else if Path::new(&path).exists() {
+        fs::remove_file(path).expect("Failed to remove state file");
+    }
+}
+
+pub fn get_config() -> HashMap<String, String> {
+    let mut values = HashMap::new();
+    let dir = RGIT_DIR.lock().unwrap().to_owned();
+    let config_path = format!("{}/config", dir.clone());
+    let path = Path::new(config_path.as_str());
+
+    if path.exists() {
+        let config_content = fs::read_to_string(path).expect("Failed to read config file");
+        let config: Config = serde_json::from_str(config_content.as_str()).unwrap();
+        values = config.values;
+    }
+
+    values
+}
+
+pub fn set_config(values: HashMap<String, String>) {
+    let new_config = Config { values };
+    let config_content = serde_json::to_string(&new_config).expect("Failed to serialize config");
+
+    let dir = RGIT_DIR.lock().unwrap().to_owned();
+    let config_path = format!("{}/config", dir.clone());
+    let path = Path::new(config_path.as_str());
+
+    fs::write(path, config_content).expect("Failed to write config");
+}
+
+pub fn get_config_value(key: &str) -> String {
+    get_config().get(key).cloned().unwrap_or("".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::with_repo;
+    use std::panic;
+
+    fn oid(digit: &str) -> RefValue {
+        RefValue {
+            symbolic: false,
+            value: digit.repeat(40),
+        }
+    }
+
+    fn list_locks() -> Vec<String> {
+        WalkDir::new(".rgit")
+            .into_iter()
+            .map(|entry| entry.unwrap().path().to_str().unwrap().to_owned())
+            .filter(|path| path.ends_with(".lock"))
+            .collect()
+    }
+
+    #[test]
+    fn failed_transaction_releases_its_locks() {
+        with_repo(|| {
+            update_ref("refs/heads/a".to_owned(), oid("1"), false, "");
+            // refs/heads/a is a file, so refs/heads/a/b cannot be created
+            let result = panic::catch_unwind(|| {
+                let mut transaction = RefTransaction::new();
+                transaction.update("refs/heads/c".to_owned(), oid("2"), false, None, "");
+                transaction.delete("refs/heads/a".to_owned(), false, None);
+                transaction.update("refs/heads/a/b".to_owned(), oid("2"), false, None, "");
+                transaction.commit();
+            });
+            assert!(result.is_err());
+            assert!(list_locks().is_empty());
+            assert_eq!(
+                get_ref("refs/heads/a".to_owned(), false).value,
+                "1".repeat(40)
+            );
+            assert_eq!(get_ref("refs/heads/c".to_owned(), false).value, "");
+
+            // Nothing is left in the way of the next update
+            update_ref("refs/heads/a".to_owned(), oid("3"), false, "");
+            assert_eq!(
+                get_ref("refs/heads/a".to_owned(), false).value,
+                "3".repeat(40)
+            );
+        });
+    }
+
+    #[test]
+    fn unexpected_value_releases_its_locks() {
+        with_repo(|| {
+            update_ref("refs/heads/a".to_owned(), oid("1"), false, "");
+            let result = panic::catch_unwind(|| {
+                let mut transaction = RefTransaction::new();
+                let expected = Some("2".repeat(40));
+                transaction.update("refs/heads/a".to_owned(), oid("3"), false, expected, "");
+                transaction.commit();
+            });
+            assert!(result.is_err());
+            assert!(list_locks().is_empty());
+            assert_eq!(
+                get_ref("refs/heads/a".to_owned(), false).value,
+                "1".repeat(40)
+            );
+        });
+    }
+
+    #[test]
+    fn same_ref_twice_is_a_duplicate_update() {
+        with_repo(|| {
+            let result = panic::catch_unwind(|| {
+                let mut transaction = RefTransaction::new();
+                transaction.update("refs/heads/a".to_owned(), oid("1"), false, None, "");
+                transaction.update("refs/heads/a".to_owned(), oid("2"), false, None, "");
+                transaction.commit();
+            });
+            let error = result.unwrap_err();
+            let message = error.downcast_ref::<String>().unwrap();
+            assert!(
+                message.contains("twice in the same transaction"),
+                "{}",
+                message
+            );
+            assert!(list_locks().is_empty());
+        });
+    }
+}
@@ -4,6 +4,7 @@ use std::process::{Command, Stdio};
 use tempfile::NamedTempFile;
 
 #[path = "data.rs"]
+#[allow(dead_code)]
 mod data;
 
 fn compare_trees(trees: Vec<HashMap<String, String>>) -> HashMap<String, Vec<String>> {
@@ -21,19 +22,19 @@ fn compare_trees(trees: Vec<HashMap<String, String>>) -> HashMap<String, Vec<Str
         }
     }
 
-    return entries;
+    entries
 }
 
 fn diff_blobs(o_from: String, o_to: String, path: String) -> String {
     let f_from = NamedTempFile::new().unwrap();
     let f_to = NamedTempFile::new().unwrap();
 
-    if o_from != "" {
+    if !o_from.is_empty() {
         let content = data::get_object(o_from, "blob".to_owned());
         fs::write(f_from.path(), content).unwrap();
     }
 
-    if o_to != "" {
+    if !o_to.is_empty() {
         let content = data::get_object(o_to, "blob".to_owned());
         fs::write(f_to.path(), content).unwrap();
     }
@@ -45,27 +46,62 @@ fn diff_blobs(o_from: String, o_to: String, path: String) -> String {
         .arg(format!("a/{}", path))
         .arg(f_from.path())
         .arg("--label")
-        .arg(format!("a/{}", path))
+        .arg(format!("b/{}", path))
         .arg(f_to.path())
         .stdout(Stdio::piped())
         .output()
         .expect("Failed to launch diff");
 
-    return String::from_utf8_lossy(&output.stdout).to_string();
+    String::from_utf8_lossy(&output.stdout).to_string()
 }
 
 pub fn diff_trees(t_from: HashMap<String, String>, t_to: HashMap<String, String>) -> String {
     let mut output = "".to_owned();
     let trees = vec![t_from, t_to];
-    for (path, oids) in compare_trees(trees).iter() {
-        let o_from = oids[0].clone();
-        let o_to = oids[1].clone();
+    let entries = compare_trees(trees);
+    let mut paths: Vec<&String> = entries.keys().collect();
+    paths.sort();
+    for path in paths {
+        let o_from = entries[path][0].clone();
+        let o_to = entries[path][1].clone();
         if o_from != o_to {
             output.push_str(diff_blobs(o_from, o_to, path.clone()).as_str());
         }
     }
 
-    return output;
+    output
+}
+
+pub fn filter_tree(tree: HashMap<String, String>, pathspecs: &[String]) -> HashMap<String, String> {
+    // Keeps only the entries that match one of the given paths, either the
+    // exact file or anything inside a matching directory.
+    if pathspecs.is_empty() {
+        return tree;
+    }
+
+    let prefixes: Vec<String> = pathspecs
+        .iter()
+        .map(|spec| {
+            spec.trim_start_matches("./")
+                .trim_end_matches("/")
+                .to_owned()
+        })
+        .collect();
+
+    let mut result = HashMap::new();
+    for (path, oid) in tree {
+        for prefix in prefixes.iter() {
+            if prefix.is_empty()
+                || *prefix == "."
+                || path == *prefix
+                || path.starts_with(&format!("{}/", prefix))
+            {
+                result.insert(path.clone(), oid.clone());
+                break;
+            }
+        }
+    }
+    result
 }
 
 pub fn changed_files(
@@ -78,9 +114,9 @@ pub fn changed_files(
         let o_from = oids[0].clone();
         let o_to = oids[1].clone();
         if o_from != o_to {
-            let action = if o_from == "" {
+            let action = if o_from.is_empty() {
                 "new file"
-            } else if o_to == "" {
+            } else if o_to.is_empty() {
                 "deleted"
             } else {
                 "mofified"
@@ -88,59 +124,507 @@ pub fn changed_files(
             result.push((path.clone(), action.to_owned()))
         }
     }
-    return result;
+    result
+}
+
+pub struct MergedFile {
+    pub content: String,
+    // Kind of conflict ("content", "add/add", "modify/delete" or
+    // "file/directory"), empty when the merge was clean
+    pub conflict: String,
+    // Where the file is written in the working tree, only different from the
+    // merged path when it clashes with a directory
+    pub path: String,
 }
 
 pub fn merge_trees(
     t_base: HashMap<String, String>,
     t_head: HashMap<String, String>,
     t_other: HashMap<String, String>,
-) -> HashMap<String, String> {
+    style: &str,
+    favor: &str,
+) -> HashMap<String, MergedFile> {
     let mut tree = HashMap::new();
-    let trees = vec![t_base, t_head, t_other];
+    let trees = vec![t_base, t_head.clone(), t_other];
     for (path, oids) in compare_trees(trees).iter() {
+        let o_base = oids[0].clone();
+        let o_head = oids[1].clone();
+        let o_other = oids[2].clone();
+
+        let (oid, conflict) = if o_head == o_other || o_other == o_base {
+            (o_head, "")
+        } else if o_head == o_base {
+            (o_other, "")
+        } else if o_head.is_empty() || o_other.is_empty() {
+            // Deleted on one side and modified on the other, keep the
+            // modified version around
+            let modified = if o_head.is_empty() { o_other } else { o_head };
+            (modified, "modify/delete")
+        } else {
+            let kind = if o_base.is_empty() {
+                "add/add"
+            } else {
+                "content"
+            };
+            let merged = merge_blobs(o_base, o_head, o_other, style, favor);
+            let conflict = if !merged.conflict.is_empty() {
+                kind
+            } else {
+                ""
+            };
+            tree.insert(
+                path.clone(),
+                MergedFile {
+                    content: merged.content,
+                    conflict: conflict.to_owned(),
+                    path: path.clone(),
+                },
+            );
+            continue;
+        };
+
+        if oid.is_empty() {
+            // Deleted
+            continue;
+        }
         tree.insert(
             path.clone(),
-            merge_blobs(oids[0].clone(), oids[1].clone(), oids[2].clone()),
+            MergedFile {
+                content: data::get_object(oid, "blob".to_owned()),
+                conflict: conflict.to_owned(),
+                path: path.clone(),
+            },
         );
     }
-    return tree;
+
+    // A file that is also a directory on the other side cannot be written to
+    // the working tree, so it is moved aside with the name of its branch
+    let paths: Vec<String> = tree.keys().cloned().collect();
+    for path in paths.iter() {
+        let dir_prefix = format!("{}/", path);
+        if !paths.iter().any(|other| other.starts_with(&dir_prefix)) {
+            continue;
+        }
+        let side = if t_head.contains_key(path) {
+            "HEAD"
+        } else {
+            "MERGE_HEAD"
+        };
+        let file = tree.get_mut(path).unwrap();
+        file.conflict = "file/directory".to_owned();
+        file.path = format!("{}~{}", path, side);
+    }
+
+    tree
+}
+
+fn merge_blobs(
+    o_base: String,
+    o_head: String,
+    o_other: String,
+    style: &str,
+    favor: &str,
+) -> MergedFile {
+    let mut contents = vec![];
+    for oid in [o_base, o_head, o_other].iter() {
+        if !oid.is_empty() {
+            contents.push(data::get_object(oid.clone(), "blob".to_owned()));
+        } else {
+            contents.push("".to_owned());
+        }
+    }
+
+    let (content, conflicted) = merge_lines(&contents[0], &contents[1], &contents[2], style, favor);
+    MergedFile {
+        content,
+        conflict: if conflicted { "content" } else { "" }.to_owned(),
+        path: "".to_owned(),
+    }
 }
 
-fn merge_blobs(o_base: String, o_head: String, o_other: String) -> String {
-    let f_base = NamedTempFile::new().unwrap();
-    let f_head = NamedTempFile::new().unwrap();
-    let f_other = NamedTempFile::new().unwrap();
+fn merge_lines(base: &str, head: &str, other: &str, style: &str, favor: &str) -> (String, bool) {
+    // Three-way line merge, similar to "diff3 -m". The changes made by each
+    // side (against the base) are grouped into regions, when both sides touch
+    // the same region with different results we have a conflict, unless one
+    // of the sides is favored ("ours" or "theirs") or both are kept ("union").
+    let base_lines: Vec<&str> = base.split_inclusive("\n").collect();
+    let head_lines: Vec<&str> = head.split_inclusive("\n").collect();
+    let other_lines: Vec<&str> = other.split_inclusive("\n").collect();
 
-    if o_base != "" {
-        let content = data::get_object(o_base, "blob".to_owned());
-        fs::write(f_base.path(), content).unwrap();
+    // (base start, base end, side, side start, side end)
+    let mut hunks: Vec<(usize, usize, usize, usize, usize)> = vec![];
+    for (side, lines) in [&head_lines, &other_lines].iter().enumerate() {
+        for hunk in diff_lines(&base_lines, lines) {
+            hunks.push((hunk.0, hunk.1, side, hunk.2, hunk.3));
+        }
     }
+    hunks.sort();
+
+    let mut output = String::new();
+    let mut conflicted = false;
+    let mut base_pos = 0;
+    let mut i = 0;
+
+    while i < hunks.len() {
+        // Find every hunk that overlaps (or touches) the current region
+        let region_start = hunks[i].0;
+        let mut region_end = hunks[i].1;
+        let mut j = i + 1;
+        while j < hunks.len() && hunks[j].0 <= region_end {
+            region_end = region_end.max(hunks[j].1);
+            j += 1;
+        }
+
+        for line in &base_lines[base_pos..region_start] {
+            output.push_str(line);
+        }
 
-    if o_head != "" {
-        let content = data::get_object(o_head, "blob".to_owned());
-        fs::write(f_head.path(), content).unwrap();
+        // Range covered by the region on each side
+        let mut ranges = vec![];
+        for (side, lines) in [&head_lines, &other_lines].iter().enumerate() {
+            let side_hunks: Vec<&(usize, usize, usize, usize, usize)> =
+                hunks[i..j].iter().filter(|h| h.2 == side).collect();
+            let range = if side_hunks.is_empty() {
+                None
+            } else {
+                let first = side_hunks[0];
+                let last = side_hunks[side_hunks.len() - 1];
+                Some(&lines[first.3 - (first.0 - region_start)..last.4 + (region_end - last.1)])
+            };
+            ranges.push(range);
+        }
+
+        let base_region = &base_lines[region_start..region_end];
+        match (ranges[0], ranges[1]) {
+            (Some(ours), None) => output.push_str(&ours.concat()),
+            (None, Some(theirs)) => output.push_str(&theirs.concat()),
+            (Some(ours), Some(theirs)) => {
+                if ours == theirs || favor == "ours" {
+                    output.push_str(&ours.concat());
+                } else if favor == "theirs" {
+                    output.push_str(&theirs.concat());
+                } else if favor == "union" {
+                    output.push_str(&ours.concat());
+                    output.push_str(&theirs.concat());
+                } else {
+                    conflicted = true;
+                    output.push_str(&conflict_markers(base_region, ours, theirs, style));
+                }
+            }
+            (None, None) => (),
+        }
+
+        base_pos = region_end;
+        i = j;
     }
 
-    if o_other != "" {
-        let content = data::get_object(o_other, "blob".to_owned());
-        fs::write(f_other.path(), content).unwrap();
+    for line in &base_lines[base_pos..] {
+        output.push_str(line);
     }
 
-    let output = Command::new("diff3")
-        .arg("-m")
-        .arg("-L")
-        .arg("HEAD")
-        .arg(f_head.path())
-        .arg("-L")
-        .arg("BASE")
-        .arg(f_base.path())
-        .arg("-L")
-        .arg("MERGE_HEAD")
-        .arg(f_other.path())
-        .stdout(Stdio::piped())
-        .output()
-        .expect("Failed to merge file");
+    (output, conflicted)
+}
+
+fn conflict_markers(base: &[&str], ours: &[&str], theirs: &[&str], style: &str) -> String {
+    let mut prefix = 0;
+    let mut suffix = 0;
+    if style == "zdiff3" {
+        // Lines shared by both sides at the start and the end of the conflict
+        // are moved out of it
+        while prefix < ours.len() && prefix < theirs.len() && ours[prefix] == theirs[prefix] {
+            prefix += 1;
+        }
+        while suffix < ours.len() - prefix
+            && suffix < theirs.len() - prefix
+            && ours[ours.len() - 1 - suffix] == theirs[theirs.len() - 1 - suffix]
+        {
+            suffix += 1;
+        }
+    }
+
+    let mut output = ours[..prefix].concat();
+    output.push_str("<<<<<<< HEAD\n");
+    push_conflict_side(&mut output, &ours[prefix..ours.len() - suffix]);
+    if style == "diff3" || style == "zdiff3" {
+        output.push_str("||||||| BASE\n");
+        push_conflict_side(&mut output, base);
+    }
+    output.push_str("=======\n");
+    push_conflict_side(&mut output, &theirs[prefix..theirs.len() - suffix]);
+    output.push_str(">>>>>>> MERGE_HEAD\n");
+    output.push_str(&ours[ours.len() - suffix..].concat());
+    output
+}
+
+fn push_conflict_side(output: &mut String, lines: &[&str]) {
+    let content = lines.concat();
+    output.push_str(&content);
+    // Markers must always start on their own line
+    if !content.is_empty() && !content.ends_with("\n") {
+        output.push('\n');
+    }
+}
+
+fn diff_lines(a: &[&str], b: &[&str]) -> Vec<(usize, usize, usize, usize)> {
+    // Returns the changed ranges (a start, a end, b start, b end) between two
+    // lists of lines, based on their longest common subsequence.
+    let mut prefix = 0;
+    while prefix < a.len() && prefix < b.len() && a[prefix] == b[prefix] {
+        prefix += 1;
+    }
+    let mut suffix = 0;
+    while suffix < a.len() - prefix
+        && suffix < b.len() - prefix
+        && a[a.len() - 1 - suffix] == b[b.len() - 1 - suffix]
+    {
+        suffix += 1;
+    }
 
-    return String::from_utf8_lossy(&output.stdout).to_string();
+    let a_mid = &a[prefix..a.len() - suffix];
+    let b_mid = &b[prefix..b.len() - suffix];
+    let n = a_mid.len();
+    let m = b_mid.len();
+
+    let mut matches = vec![];
+    lcs_matches(a_mid, b_mid, 0, 0, &mut matches);
+
+    let mut hunks = vec![];
+    let (mut hunk_x, mut hunk_y) = (0, 0);
+    for (x, y) in matches {
+        if hunk_x != x || hunk_y != y {
+            hunks.push((hunk_x + prefix, x + prefix, hunk_y + prefix, y + prefix));
+        }
+        hunk_x = x + 1;
+        hunk_y = y + 1;
+    }
+    if hunk_x != n || hunk_y != m {
+        hunks.push((hunk_x + prefix, n + prefix, hunk_y + prefix, m + prefix));
+    }
+
+    hunks
+}
+
+fn lcs_matches(
+    a: &[&str],
+    b: &[&str],
+    a_start: usize,
+    b_start: usize,
+    matches: &mut Vec<(usize, usize)>,
+) {
+    // Adds the pairs of equal lines of a longest common subsequence, in
+    // order. The sequences are split in two (Hirschberg), so only a couple
+    // of rows are kept in memory instead of the whole table.
+    if a.is_empty() || b.is_empty() {
+        return;
+    }
+    if a.len() == 1 {
+        if let Some(y) = b.iter().position(|line| *line == a[0]) {
+            matches.push((a_start, b_start + y));
+        }
+        return;
+    }
+
+    let middle = a.len() / 2;
+    let forward = lcs_row(&a[..middle], b, false);
+    let backward = lcs_row(&a[middle..], b, true);
+    let split = (0..=b.len())
+        .max_by_key(|y| (forward[*y] + backward[b.len() - y], std::cmp::Reverse(*y)))
+        .unwrap();
+
+    lcs_matches(&a[..middle], &b[..split], a_start, b_start, matches);
+    lcs_matches(
+        &a[middle..],
+        &b[split..],
+        a_start + middle,
+        b_start + split,
+        matches,
+    );
+}
+
+fn lcs_row(a: &[&str], b: &[&str], reverse: bool) -> Vec<usize> {
+    // row[y] is the LCS length of a and the first y lines of b, or of the
+    // last y lines when comparing from the end
+    let m = b.len();
+    let mut row = vec![0; m + 1];
+    for x in 0..a.len() {
+        let line = if reverse { a[a.len() - 1 - x] } else { a[x] };
+        let mut diagonal = 0;
+        for y in 1..=m {
+            let other = if reverse { b[m - y] } else { b[y - 1] };
+            let above = row[y];
+            row[y] = if line == other {
+                diagonal + 1
+            } else {
+                above.max(row[y - 1])
+            };
+            diagonal = above;
+        }
+    }
+    row
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::with_repo;
+
+    fn tree(files: &[(&str, &str)]) -> HashMap<String, String> {
+        files
+            .iter()
+            .map(|(path, content)| {
+                let oid = data::hash_object(content.as_bytes(), "blob".to_owned());
+                (path.to_string(), oid)
+            })
+            .collect()
+    }
+
+    fn merge(
+        base: &[(&str, &str)],
+        head: &[(&str, &str)],
+        other: &[(&str, &str)],
+    ) -> HashMap<String, MergedFile> {
+        merge_trees(tree(base), tree(head), tree(other), "merge", "")
+    }
+
+    fn lines(text: &str) -> Vec<&str> {
+        text.split_inclusive("\n").collect()
+    }
+
+    #[test]
+    fn diff_lines_finds_the_changed_ranges() {
+        let a = lines("a\nb\nc\nd\ne\n");
+        let b = lines("a\nB\nc\nd\nx\ne\n");
+        assert_eq!(diff_lines(&a, &b), vec![(1, 2, 1, 2), (4, 4, 4, 5)]);
+        assert_eq!(diff_lines(&a, &a), vec![]);
+        assert_eq!(diff_lines(&a, &[]), vec![(0, 5, 0, 0)]);
+
+        // Changes far apart in a long file
+        let old: String = (0..1000).map(|i| format!("{}\n", i)).collect();
+        let new = old
+            .replace("\n100\n", "\n")
+            .replace("\n900\n", "\n900\nx\n");
+        assert_eq!(
+            diff_lines(&lines(&old), &lines(&new)),
+            vec![(100, 101, 100, 100), (901, 901, 900, 901)]
+        );
+    }
+
+    #[test]
+    fn separate_changes_merge_cleanly() {
+        let (output, conflicted) = merge_lines(
+            "a\nb\nc\nd\ne\n",
+            "a\nB\nc\nd\ne\n",
+            "a\nb\nc\nD\ne\n",
+            "merge",
+            "",
+        );
+        assert!(!conflicted);
+        assert_eq!(output, "a\nB\nc\nD\ne\n");
+    }
+
+    #[test]
+    fn overlapping_changes_conflict_in_every_style() {
+        let (base, ours, theirs) = ("a\nb\nc\n", "a\nX\nZ\nc\n", "a\nY\nZ\nc\n");
+        let (output, conflicted) = merge_lines(base, ours, theirs, "merge", "");
+        assert!(conflicted);
+        assert_eq!(
+            output,
+            "a\n<<<<<<< HEAD\nX\nZ\n=======\nY\nZ\n>>>>>>> MERGE_HEAD\nc\n"
+        );
+
+        let (output, _) = merge_lines(base, ours, theirs, "diff3", "");
+        assert_eq!(
+            output,
+            "a\n<<<<<<< HEAD\nX\nZ\n||||||| BASE\nb\n=======\nY\nZ\n>>>>>>> MERGE_HEAD\nc\n"
+        );
+
+        // The line both sides added at the end is moved out of the conflict
+        let (output, _) = merge_lines(base, ours, theirs, "zdiff3", "");
+        assert_eq!(
+            output,
+            "a\n<<<<<<< HEAD\nX\n||||||| BASE\nb\n=======\nY\n>>>>>>> MERGE_HEAD\nZ\nc\n"
+        );
+    }
+
+    #[test]
+    fn conflict_markers_start_on_their_own_line() {
+        let output = conflict_markers(&["b"], &["x"], &["y"], "merge");
+        assert_eq!(output, "<<<<<<< HEAD\nx\n=======\ny\n>>>>>>> MERGE_HEAD\n");
+    }
+
+    #[test]
+    fn favored_side_resolves_conflicts() {
+        let (base, ours, theirs) = ("a\nb\nc\n", "a\nX\nc\n", "a\nY\nc\n");
+        assert_eq!(
+            merge_lines(base, ours, theirs, "merge", "ours"),
+            ("a\nX\nc\n".to_owned(), false)
+        );
+        assert_eq!(
+            merge_lines(base, ours, theirs, "merge", "theirs"),
+            ("a\nY\nc\n".to_owned(), false)
+        );
+        assert_eq!(
+            merge_lines(base, ours, theirs, "merge", "union"),
+            ("a\nX\nY\nc\n".to_owned(), false)
+        );
+    }
+
+    #[test]
+    fn one_sided_changes_are_clean() {
+        with_repo(|| {
+            let result = merge(
+                &[("kept", "a\n"), ("changed", "a\n"), ("deleted", "a\n")],
+                &[("kept", "a\n"), ("changed", "a\n"), ("deleted", "a\n")],
+                &[("kept", "a\n"), ("changed", "b\n"), ("added", "c\n")],
+            );
+            assert_eq!(result.len(), 3);
+            assert!(!result.contains_key("deleted"));
+            assert_eq!(result["changed"].content, "b\n");
+            assert_eq!(result["added"].content, "c\n");
+            assert!(result.values().all(|file| file.conflict.is_empty()));
+        });
+    }
+
+    #[test]
+    fn modify_delete_keeps_the_modified_side() {
+        with_repo(|| {
+            let result = merge(&[("f", "a\n")], &[], &[("f", "b\n")]);
+            assert_eq!(result["f"].conflict, "modify/delete");
+            assert_eq!(result["f"].content, "b\n");
+            assert_eq!(result["f"].path, "f");
+
+            let result = merge(&[("f", "a\n")], &[("f", "b\n")], &[]);
+            assert_eq!(result["f"].conflict, "modify/delete");
+            assert_eq!(result["f"].content, "b\n");
+        });
+    }
+
+    #[test]
+    fn add_add_conflicts_unless_identical() {
+        with_repo(|| {
+            let result = merge(&[], &[("f", "ours\n")], &[("f", "theirs\n")]);
+            assert_eq!(result["f"].conflict, "add/add");
+            assert!(result["f"].content.contains("<<<<<<< HEAD\nours\n"));
+            assert!(result["f"].content.contains("theirs\n>>>>>>> MERGE_HEAD\n"));
+
+            let result = merge(&[], &[("f", "same\n")], &[("f", "same\n")]);
+            assert_eq!(result["f"].conflict, "");
+            assert_eq!(result["f"].content, "same\n");
+        });
+    }
+
+    #[test]
+    fn file_directory_moves_the_file_aside() {
+        with_repo(|| {
+            let result = merge(&[], &[("d", "file\n")], &[("d/x", "x\n")]);
+            assert_eq!(result["d"].conflict, "file/directory");
+            assert_eq!(result["d"].path, "d~HEAD");
+            assert_eq!(result["d/x"].conflict, "");
+            assert_eq!(result["d/x"].path, "d/x");
+
+            let result = merge(&[], &[("d/x", "x\n")], &[("d", "file\n")]);
+            assert_eq!(result["d"].conflict, "file/directory");
+            assert_eq!(result["d"].path, "d~MERGE_HEAD");
+        });
+    }
 }
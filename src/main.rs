@@ -1,18 +1,50 @@
+// Modules are also included by each other through #[path], so the same file
+// is compiled several times and every copy only uses part of it.
+#![allow(clippy::duplicate_mod)]
+
 use clap::{App, Arg, ArgMatches, SubCommand};
 use std::collections::{HashMap, VecDeque};
 use std::fs;
 use std::io::Write;
 use std::process::{Command, Stdio};
+#[allow(dead_code)]
 mod base;
+mod color;
+#[allow(dead_code)]
 mod data;
+#[allow(dead_code)]
 mod diff;
+mod pager;
+mod rebase;
 mod remote;
+#[allow(dead_code)]
+mod sequencer;
+#[allow(dead_code)]
+mod sign;
+mod stash;
+#[cfg(test)]
+mod test_utils;
 
 fn main() {
     let matches = App::new("rgit vcs")
         .version("0.1.0")
         .author("Gonçalo Valério <gon@ovalerio.net>")
         .about("A watered-down git clone")
+        .arg(
+            Arg::with_name("color")
+                .long("color")
+                .value_name("WHEN")
+                .help("When to color the output, defaults to the color.ui config")
+                .possible_values(&["auto", "always", "never"])
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("no-pager")
+                .long("no-pager")
+                .help("Do not pipe the output into a pager")
+                .global(true),
+        )
         .subcommand(SubCommand::with_name("init").about("creates new repository"))
         .subcommand(
             SubCommand::with_name("hash-object")
@@ -22,7 +54,12 @@ fn main() {
         .subcommand(
             SubCommand::with_name("cat-file")
                 .about("outputs the original object from the provided hash")
-                .arg(Arg::with_name("hash").index(1).required(true)),
+                .arg(Arg::with_name("hash").index(1).required(true))
+                .arg(
+                    Arg::with_name("type")
+                        .short("t")
+                        .help("Show the type of the object instead"),
+                ),
         )
         .subcommand(
             SubCommand::with_name("write-tree")
@@ -41,30 +78,161 @@ fn main() {
                         .short("m")
                         .value_name("MESSAGE")
                         .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("sign")
+                        .short("S")
+                        .long("sign")
+                        .help("Sign the commit with the signing key"),
                 ),
         )
         .subcommand(
             SubCommand::with_name("log")
                 .about("List all commits")
-                .arg(Arg::with_name("oid").index(1).default_value("@")),
+                .arg(
+                    Arg::with_name("oid")
+                        .index(1)
+                        .multiple(true)
+                        .default_value("@")
+                        .help("Revisions or ranges (A..B, A...B, ^A) to list"),
+                )
+                .arg(
+                    Arg::with_name("show-signature")
+                        .long("show-signature")
+                        .help("Check the signature of each commit"),
+                ),
         )
         .subcommand(
             SubCommand::with_name("checkout")
                 .about("Move the current content and HEAD to given commit")
-                .arg(Arg::with_name("commit").index(1).required(true)),
+                .arg(Arg::with_name("commit").index(1))
+                .arg(
+                    Arg::with_name("paths")
+                        .multiple(true)
+                        .last(true)
+                        .help("Only restore these paths, from the commit or else the index"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("switch")
+                .about("Switch to a branch")
+                .arg(Arg::with_name("branch").index(1).required(true))
+                .arg(
+                    Arg::with_name("start_point")
+                        .index(2)
+                        .default_value("@")
+                        .help("Where the branch created with -c starts"),
+                )
+                .arg(
+                    Arg::with_name("create")
+                        .short("c")
+                        .long("create")
+                        .help("Create the branch before switching to it"),
+                )
+                .arg(
+                    Arg::with_name("detach")
+                        .long("detach")
+                        .help("Switch to a commit, detaching HEAD"),
+                ),
         )
         .subcommand(
             SubCommand::with_name("tag")
-                .about("Create a tag for a given commit")
-                .arg(Arg::with_name("name").index(1).required(true))
-                .arg(Arg::with_name("oid").index(2).default_value("@")),
+                .about("Create a tag for a given commit, or list the tags")
+                .arg(Arg::with_name("name").index(1))
+                .arg(Arg::with_name("oid").index(2).default_value("@"))
+                .arg(
+                    Arg::with_name("annotate")
+                        .short("a")
+                        .long("annotate")
+                        .help("Create a tag object with a message and a tagger"),
+                )
+                .arg(
+                    Arg::with_name("message")
+                        .short("m")
+                        .long("message")
+                        .takes_value(true)
+                        .help("Message of the tag, implies -a"),
+                )
+                .arg(
+                    Arg::with_name("sign")
+                        .short("s")
+                        .long("sign")
+                        .help("Create a signed annotated tag"),
+                )
+                .arg(
+                    Arg::with_name("list")
+                        .short("l")
+                        .long("list")
+                        .help("List the tags, only those matching the name if given"),
+                ),
         )
         .subcommand(SubCommand::with_name("k").about("visualize refs and commits"))
         .subcommand(
             SubCommand::with_name("branch")
-                .about("Create a new branch")
+                .about("List, create, move, rename or delete branches")
                 .arg(Arg::with_name("name").index(1).required(false))
-                .arg(Arg::with_name("start_point").index(2).default_value("@")),
+                .arg(Arg::with_name("start_point").index(2).default_value("@"))
+                .arg(
+                    Arg::with_name("delete")
+                        .short("d")
+                        .long("delete")
+                        .help("Delete a branch merged into HEAD"),
+                )
+                .arg(
+                    Arg::with_name("force-delete")
+                        .short("D")
+                        .help("Delete a branch even if it is not merged"),
+                )
+                .arg(
+                    Arg::with_name("move")
+                        .short("m")
+                        .long("move")
+                        .help("Rename a branch, the current one if only a new name is given"),
+                )
+                .arg(
+                    Arg::with_name("force-move")
+                        .short("M")
+                        .help("Rename a branch even if the new name exists"),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .short("f")
+                        .long("force")
+                        .help("Move the branch to the start point if it already exists"),
+                )
+                .arg(
+                    Arg::with_name("verbose")
+                        .short("v")
+                        .long("verbose")
+                        .help("Show the commit and subject of each branch"),
+                )
+                .arg(
+                    Arg::with_name("set-upstream-to")
+                        .short("u")
+                        .long("set-upstream-to")
+                        .value_name("UPSTREAM")
+                        .takes_value(true)
+                        .help("Make the branch (the current one by default) track another"),
+                )
+                .arg(
+                    Arg::with_name("unset-upstream")
+                        .long("unset-upstream")
+                        .help("Stop tracking an upstream"),
+                )
+                .arg(
+                    Arg::with_name("merged")
+                        .long("merged")
+                        .value_name("COMMIT")
+                        .min_values(0)
+                        .help("Only list the branches merged into the commit (HEAD by default)"),
+                )
+                .arg(
+                    Arg::with_name("no-merged")
+                        .long("no-merged")
+                        .value_name("COMMIT")
+                        .min_values(0)
+                        .help("Only list the branches not merged into the commit"),
+                ),
         )
         .subcommand(SubCommand::with_name("status").about("check current branch"))
         .subcommand(
@@ -79,29 +247,391 @@ fn main() {
         )
         .subcommand(
             SubCommand::with_name("diff")
-                .about("Compare the working tree with the given commit")
-                .arg(Arg::with_name("commit").index(1).default_value("@")),
+                .about("Compare the index, the working tree and commits")
+                .arg(
+                    Arg::with_name("cached")
+                        .long("cached")
+                        .help("Compare the index with the given commit (defaults to HEAD)"),
+                )
+                .arg(Arg::with_name("commits").index(1).multiple(true))
+                .arg(Arg::with_name("paths").multiple(true).last(true)),
         )
         .subcommand(
             SubCommand::with_name("merge")
                 .about("Merge changes of a different commit/branch")
-                .arg(Arg::with_name("commit").index(1).required(true)),
+                .arg(
+                    Arg::with_name("commit")
+                        .index(1)
+                        .multiple(true)
+                        .required_unless_one(&["abort", "continue"]),
+                )
+                .arg(
+                    Arg::with_name("strategy")
+                        .short("s")
+                        .long("strategy")
+                        .takes_value(true)
+                        .possible_values(&["recursive", "resolve", "ours"])
+                        .default_value("recursive")
+                        .help("Merge strategy, \"ours\" keeps the current tree as it is"),
+                )
+                .arg(
+                    Arg::with_name("strategy-option")
+                        .short("X")
+                        .long("strategy-option")
+                        .takes_value(true)
+                        .possible_values(&["ours", "theirs", "union"])
+                        .help("Resolve the conflicting hunks using our side, their side or both"),
+                )
+                .arg(
+                    Arg::with_name("no-ff")
+                        .long("no-ff")
+                        .conflicts_with("ff-only")
+                        .help("Create a merge commit even when a fast-forward is possible"),
+                )
+                .arg(
+                    Arg::with_name("ff-only")
+                        .long("ff-only")
+                        .help("Refuse to merge unless it is a fast-forward"),
+                )
+                .arg(
+                    Arg::with_name("squash")
+                        .long("squash")
+                        .conflicts_with("no-ff")
+                        .help("Stage the merged changes to be committed as a regular commit"),
+                )
+                .arg(
+                    Arg::with_name("abort")
+                        .long("abort")
+                        .conflicts_with_all(&["commit", "continue"])
+                        .help("Go back to the state before the merge"),
+                )
+                .arg(
+                    Arg::with_name("continue")
+                        .long("continue")
+                        .conflicts_with("commit")
+                        .help("Commit the merge after the conflicts are resolved"),
+                ),
         )
         .subcommand(
             SubCommand::with_name("merge-base")
                 .about("Find the common ancestor between two commits")
+                .arg(
+                    Arg::with_name("all")
+                        .long("all")
+                        .help("Output all the best common ancestors"),
+                )
                 .arg(Arg::with_name("commit1").index(1).required(true))
                 .arg(Arg::with_name("commit2").index(2).required(true)),
         )
+        .subcommand(
+            SubCommand::with_name("cherry-pick")
+                .about("Apply the changes introduced by existing commits")
+                .arg(
+                    Arg::with_name("commits")
+                        .index(1)
+                        .multiple(true)
+                        .required_unless_one(&["abort", "continue"]),
+                )
+                .arg(
+                    Arg::with_name("record-origin")
+                        .short("x")
+                        .help("Append the original commit id to the message"),
+                )
+                .arg(
+                    Arg::with_name("no-commit")
+                        .short("n")
+                        .long("no-commit")
+                        .help("Apply the changes to the index without committing them"),
+                )
+                .arg(
+                    Arg::with_name("mainline")
+                        .short("m")
+                        .long("mainline")
+                        .takes_value(true)
+                        .help("Parent number of a merge commit to take the changes from"),
+                )
+                .arg(
+                    Arg::with_name("abort")
+                        .long("abort")
+                        .conflicts_with_all(&["commits", "continue"])
+                        .help("Go back to the state before the cherry-pick"),
+                )
+                .arg(
+                    Arg::with_name("continue")
+                        .long("continue")
+                        .conflicts_with("commits")
+                        .help("Resume after the conflicts are resolved"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("revert")
+                .about("Create commits that undo the changes of existing commits")
+                .arg(
+                    Arg::with_name("commits")
+                        .index(1)
+                        .multiple(true)
+                        .required_unless_one(&["abort", "continue"]),
+                )
+                .arg(
+                    Arg::with_name("no-commit")
+                        .short("n")
+                        .long("no-commit")
+                        .help("Revert the changes in the index without committing them"),
+                )
+                .arg(
+                    Arg::with_name("mainline")
+                        .short("m")
+                        .long("mainline")
+                        .takes_value(true)
+                        .help("Parent number of a merge commit to go back to"),
+                )
+                .arg(
+                    Arg::with_name("abort")
+                        .long("abort")
+                        .conflicts_with_all(&["commits", "continue"])
+                        .help("Go back to the state before the revert"),
+                )
+                .arg(
+                    Arg::with_name("continue")
+                        .long("continue")
+                        .conflicts_with("commits")
+                        .help("Resume after the conflicts are resolved"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rebase")
+                .about("Replay the commits of the current branch on top of another commit")
+                .arg(
+                    Arg::with_name("upstream")
+                        .index(1)
+                        .required_unless_one(&["abort", "continue", "skip"]),
+                )
+                .arg(
+                    Arg::with_name("onto")
+                        .long("onto")
+                        .takes_value(true)
+                        .help("Replay the commits on this commit instead of upstream"),
+                )
+                .arg(
+                    Arg::with_name("interactive")
+                        .short("i")
+                        .long("interactive")
+                        .help("Edit the list of commits to replay before starting"),
+                )
+                .arg(
+                    Arg::with_name("continue")
+                        .long("continue")
+                        .conflicts_with_all(&["upstream", "abort", "skip"])
+                        .help("Resume after the conflicts are resolved"),
+                )
+                .arg(
+                    Arg::with_name("skip")
+                        .long("skip")
+                        .conflicts_with_all(&["upstream", "abort"])
+                        .help("Drop the commit that stopped the rebase and carry on"),
+                )
+                .arg(
+                    Arg::with_name("abort")
+                        .long("abort")
+                        .conflicts_with("upstream")
+                        .help("Go back to the state before the rebase"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("stash")
+                .about("Put the local changes away and bring them back later")
+                .args(&stash_push_args())
+                .subcommand(
+                    SubCommand::with_name("push")
+                        .about("Save the local changes and go back to HEAD")
+                        .args(&stash_push_args()),
+                )
+                .subcommand(
+                    SubCommand::with_name("pop")
+                        .about("Apply a stash and remove it from the list")
+                        .arg(Arg::with_name("stash").index(1)),
+                )
+                .subcommand(
+                    SubCommand::with_name("apply")
+                        .about("Apply a stash on top of the working tree")
+                        .arg(Arg::with_name("stash").index(1)),
+                )
+                .subcommand(SubCommand::with_name("list").about("List the stashes"))
+                .subcommand(
+                    SubCommand::with_name("show")
+                        .about("Show the changes recorded in a stash")
+                        .arg(
+                            Arg::with_name("patch")
+                                .short("p")
+                                .long("patch")
+                                .help("Show the full diff"),
+                        )
+                        .arg(Arg::with_name("stash").index(1)),
+                )
+                .subcommand(
+                    SubCommand::with_name("drop")
+                        .about("Remove a stash from the list")
+                        .arg(Arg::with_name("stash").index(1)),
+                )
+                .subcommand(SubCommand::with_name("clear").about("Remove all the stashes")),
+        )
+        .subcommand(
+            SubCommand::with_name("reflog")
+                .about("Show the previous values of a ref")
+                .arg(Arg::with_name("ref").index(1).default_value("HEAD")),
+        )
+        .subcommand(
+            SubCommand::with_name("pack-refs")
+                .about("Store the refs in a single packed-refs file")
+                .arg(
+                    Arg::with_name("all")
+                        .long("all")
+                        .help("Pack every ref, not only the tags"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rev-parse")
+                .about("Print the oids that revisions point to")
+                .arg(
+                    Arg::with_name("revision")
+                        .index(1)
+                        .multiple(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("signing-key")
+                .about("Show or create the key used to sign commits and tags")
+                .arg(
+                    Arg::with_name("generate")
+                        .long("generate")
+                        .help("Create a new key pair"),
+                )
+                .arg(
+                    Arg::with_name("trust")
+                        .long("trust")
+                        .value_names(&["NAME", "KEY"])
+                        .number_of_values(2)
+                        .help("Accept signatures made with another public key"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify-commit")
+                .about("Check the signature of commits")
+                .arg(
+                    Arg::with_name("commit")
+                        .index(1)
+                        .multiple(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify-tag")
+                .about("Check the signature of annotated tags")
+                .arg(Arg::with_name("tag").index(1).multiple(true).required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("remote")
+                .about("Manage the remote repositories")
+                .arg(
+                    Arg::with_name("verbose")
+                        .short("v")
+                        .long("verbose")
+                        .help("Show the URL of each remote"),
+                )
+                .subcommand(
+                    SubCommand::with_name("add")
+                        .about("Add a remote")
+                        .arg(Arg::with_name("name").index(1).required(true))
+                        .arg(Arg::with_name("url").index(2).required(true)),
+                )
+                .subcommand(
+                    SubCommand::with_name("remove")
+                        .alias("rm")
+                        .about("Remove a remote and its remote-tracking branches")
+                        .arg(Arg::with_name("name").index(1).required(true)),
+                )
+                .subcommand(
+                    SubCommand::with_name("rename")
+                        .about("Rename a remote and its remote-tracking branches")
+                        .arg(Arg::with_name("old").index(1).required(true))
+                        .arg(Arg::with_name("new").index(2).required(true)),
+                )
+                .subcommand(
+                    SubCommand::with_name("set-url")
+                        .about("Change the URL of a remote")
+                        .arg(Arg::with_name("name").index(1).required(true))
+                        .arg(Arg::with_name("url").index(2).required(true)),
+                )
+                .subcommand(SubCommand::with_name("list").about("List the remotes")),
+        )
+        .subcommand(
+            SubCommand::with_name("clone")
+                .about("Create a copy of another repository")
+                .arg(Arg::with_name("source").index(1).required(true))
+                .arg(
+                    Arg::with_name("directory")
+                        .index(2)
+                        .help("Where to create it, named after the source by default"),
+                )
+                .arg(
+                    Arg::with_name("bare")
+                        .long("bare")
+                        .help("Only copy the repository, without a working tree"),
+                )
+                .arg(
+                    Arg::with_name("branch")
+                        .short("b")
+                        .long("branch")
+                        .takes_value(true)
+                        .help("Branch to check out instead of the one of the remote HEAD"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("pull")
+                .about("Fetch from a remote and integrate its branch into the current one")
+                .arg(
+                    Arg::with_name("remote")
+                        .index(1)
+                        .help("Remote or path, the one of the upstream by default"),
+                )
+                .arg(
+                    Arg::with_name("branch")
+                        .index(2)
+                        .help("Branch of the remote, the upstream one by default"),
+                )
+                .arg(
+                    Arg::with_name("ff-only")
+                        .long("ff-only")
+                        .conflicts_with("rebase")
+                        .help("Only update the branch if it can be fast-forwarded"),
+                )
+                .arg(
+                    Arg::with_name("rebase")
+                        .short("r")
+                        .long("rebase")
+                        .help("Rebase the current branch instead of merging, like pull.rebase"),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("fetch")
                 .about("Fetch refs and objects from another repository")
-                .arg(Arg::with_name("remote").index(1).required(true)),
+                .arg(
+                    Arg::with_name("remote")
+                        .index(1)
+                        .required(true)
+                        .help("Name of a configured remote, or the path of a repository"),
+                ),
         )
         .subcommand(
             SubCommand::with_name("push")
                 .about("Push refs and objects to another repository")
-                .arg(Arg::with_name("remote").index(1).required(true))
+                .arg(
+                    Arg::with_name("remote")
+                        .index(1)
+                        .required(true)
+                        .help("Name of a configured remote, or the path of a repository"),
+                )
                 .arg(Arg::with_name("branch").index(2).required(true)),
         )
         .subcommand(
@@ -109,9 +639,45 @@ fn main() {
                 .about("Add files to the index")
                 .arg(Arg::with_name("files").multiple(true)),
         )
+        .subcommand(
+            SubCommand::with_name("config")
+                .about("Get and set repository options")
+                .arg(Arg::with_name("list").short("l").long("list"))
+                .arg(Arg::with_name("unset").long("unset"))
+                .arg(Arg::with_name("key").index(1))
+                .arg(Arg::with_name("value").index(2)),
+        )
         .get_matches();
 
     data::set_rgit_dir(".");
+    let (command, command_matches) = matches.subcommand();
+    let global_matches = command_matches.unwrap_or(&matches);
+    color::setup(global_matches.value_of("color").unwrap_or(""));
+    let paged = ["log", "show", "diff", "reflog"];
+    if paged.contains(&command) && !global_matches.is_present("no-pager") {
+        pager::setup();
+    }
+
+    let work_tree_commands = [
+        "add",
+        "checkout",
+        "cherry-pick",
+        "commit",
+        "merge",
+        "pull",
+        "read-tree",
+        "rebase",
+        "reset",
+        "revert",
+        "stash",
+        "status",
+        "switch",
+        "write-tree",
+    ];
+    if work_tree_commands.contains(&command) {
+        base::assert_work_tree(command);
+    }
+
     match matches.subcommand_name() {
         Some("init") => init(),
         Some("hash-object") => hash_object(matches),
@@ -121,6 +687,7 @@ fn main() {
         Some("commit") => commit(matches),
         Some("log") => log_commits(matches),
         Some("checkout") => checkout(matches),
+        Some("switch") => switch(matches),
         Some("tag") => tag(matches),
         Some("k") => k(),
         Some("branch") => branch(matches),
@@ -130,11 +697,26 @@ fn main() {
         Some("diff") => difference(matches),
         Some("merge") => merge(matches),
         Some("merge-base") => merge_base(matches),
+        Some("cherry-pick") => cherry_pick(matches),
+        Some("revert") => revert(matches),
+        Some("rebase") => rebase(matches),
+        Some("stash") => stash(matches),
+        Some("reflog") => reflog(matches),
+        Some("pack-refs") => pack_refs(matches),
+        Some("rev-parse") => rev_parse(matches),
+        Some("signing-key") => signing_key(matches),
+        Some("verify-commit") => verify(matches, "verify-commit"),
+        Some("verify-tag") => verify(matches, "verify-tag"),
+        Some("remote") => remote(matches),
+        Some("clone") => clone(matches),
+        Some("pull") => pull(matches),
         Some("fetch") => fetch(matches),
         Some("push") => push(matches),
         Some("add") => add(matches),
+        Some("config") => config(matches),
         _ => println!("unknown sub command"),
     }
+    pager::finish();
     data::reset_rgit_dir();
 }
 
@@ -156,7 +738,11 @@ fn hash_object(matches: ArgMatches) {
 
 fn cat_file(matches: ArgMatches) {
     if let Some(cmd_matches) = matches.subcommand_matches("cat-file") {
-        let hash = base::get_oid(cmd_matches.value_of("hash").unwrap().to_owned());
+        let hash = base::get_object_oid(cmd_matches.value_of("hash").unwrap().to_owned());
+        if cmd_matches.is_present("type") {
+            println!("{}", data::get_object_type(&hash));
+            return;
+        }
         let file_contents = data::get_object(hash, "".to_owned());
         println!("{}", file_contents)
     }
@@ -176,34 +762,35 @@ fn read_tree(matches: ArgMatches) {
 fn commit(matches: ArgMatches) {
     if let Some(cmd_matches) = matches.subcommand_matches("commit") {
         let message = cmd_matches.value_of("message").unwrap_or("");
-        println!("{}", base::commit(message));
+        println!("{}", base::commit(message, cmd_matches.is_present("sign")));
     }
 }
 
 fn log_commits(matches: ArgMatches) {
     if let Some(cmd_matches) = matches.subcommand_matches("log") {
-        let provided_ref = cmd_matches.value_of("oid").unwrap().to_owned();
+        let revisions = cmd_matches
+            .values_of("oid")
+            .unwrap()
+            .map(|rev| rev.to_owned())
+            .collect();
 
         let mut refs: HashMap<String, Vec<String>> = HashMap::new();
-        for entry in data::iter_refs("", true) {
-            if refs.contains_key(&entry.1.value) {
-                refs.get_mut(&entry.1.value).unwrap().push(entry.0);
-            } else {
-                refs.insert(entry.1.value, vec![entry.0]);
+        for mut entry in data::iter_refs("", true) {
+            if !entry.1.value.is_empty() {
+                // Annotated tags decorate the commit they point to
+                entry.1.value = base::peel(entry.1.value, "");
             }
+            refs.entry(entry.1.value).or_default().push(entry.0);
         }
 
-        let initial_oid = base::get_oid(provided_ref.to_owned());
-        let mut oids = VecDeque::new();
-        oids.push_back(initial_oid);
-
-        for oid in base::iter_commits_and_parents(oids) {
+        let (include, exclude) = base::get_revision_range(revisions);
+        for oid in base::iter_commits_in_range(include, exclude) {
             let commit = base::get_commit(oid.clone());
 
-            print_commit(oid, &commit, refs.clone());
-
-            if commit.parents[0] == "" {
-                break;
+            print_commit(oid.clone(), &commit, refs.clone());
+            if cmd_matches.is_present("show-signature") {
+                let (_, description) = sign::verify(&oid, "commit");
+                println!("{}\n", description);
             }
         }
     }
@@ -211,20 +798,134 @@ fn log_commits(matches: ArgMatches) {
 
 fn checkout(matches: ArgMatches) {
     if let Some(cmd_matches) = matches.subcommand_matches("checkout") {
-        let name = cmd_matches.value_of("commit").unwrap().to_owned();
+        let name = cmd_matches.value_of("commit").unwrap_or("").to_owned();
+        if let Some(paths) = cmd_matches.values_of("paths") {
+            let paths = paths.map(|path| path.to_owned()).collect();
+            base::checkout_paths(name, &paths);
+            return;
+        }
+        assert!(
+            !name.is_empty(),
+            "Nothing to check out, give a commit or -- <path>..."
+        );
         base::checkout(name);
     }
 }
 
+fn switch(matches: ArgMatches) {
+    if let Some(cmd_matches) = matches.subcommand_matches("switch") {
+        let name = cmd_matches.value_of("branch").unwrap().to_owned();
+        if cmd_matches.is_present("create") {
+            let start_point = cmd_matches.value_of("start_point").unwrap().to_owned();
+            base::create_branch(name.clone(), base::get_oid(start_point), false);
+        }
+        base::switch(name, cmd_matches.is_present("detach"));
+    }
+}
+
 fn tag(matches: ArgMatches) {
     if let Some(cmd_matches) = matches.subcommand_matches("tag") {
-        let name = cmd_matches.value_of("name").unwrap().to_owned();
+        let name = cmd_matches.value_of("name").unwrap_or("").to_owned();
+        if cmd_matches.is_present("list") || name.is_empty() {
+            for tag in base::list_tags(&name) {
+                println!("{}", tag);
+            }
+            return;
+        }
+
         let provided_ref = cmd_matches.value_of("oid").unwrap().to_owned();
         let oid = base::get_oid(provided_ref.clone());
-        base::create_tag(name, oid);
+        let signed = cmd_matches.is_present("sign");
+        if let Some(message) = cmd_matches.value_of("message") {
+            base::create_annotated_tag(name, oid, message.to_owned(), signed);
+        } else {
+            assert!(
+                !cmd_matches.is_present("annotate") && !signed,
+                "Annotated tags need a message, use -m"
+            );
+            base::create_tag(name, oid);
+        }
     }
 }
 
+fn list_branches(cmd_matches: &ArgMatches) {
+    let current = base::get_branch_name();
+    let mut branches = base::iter_branch_names();
+    branches.sort();
+
+    // --merged and --no-merged keep the branches that are (or are not)
+    // ancestors of the given commit
+    for (filter, merged) in [("merged", true), ("no-merged", false)].iter() {
+        if cmd_matches.is_present(filter) {
+            let target = base::get_oid(cmd_matches.value_of(filter).unwrap_or("@").to_owned());
+            branches.retain(|branch| {
+                let tip = base::get_oid(format!("refs/heads/{}", branch));
+                base::is_ancestor_of(target.clone(), tip) == *merged
+            });
+        }
+    }
+
+    let width = branches
+        .iter()
+        .map(|branch| branch.len())
+        .max()
+        .unwrap_or(0);
+    for branch in branches {
+        let prefix = if branch == current { "*" } else { " " };
+        if cmd_matches.is_present("verbose") {
+            let oid = base::get_oid(format!("refs/heads/{}", branch));
+            let message = base::get_commit(oid.clone()).message;
+            let subject = message.lines().next().unwrap_or("");
+            let upstream = base::get_upstream(&branch);
+            let tracking = if !upstream.is_empty() {
+                match tracking_summary(oid.clone(), &upstream).as_str() {
+                    "" => format!("[{}] ", short_ref_name(&upstream)),
+                    summary => format!("[{}: {}] ", short_ref_name(&upstream), summary),
+                }
+            } else {
+                "".to_owned()
+            };
+            println!(
+                "{} {:width$} {} {}{}",
+                prefix,
+                branch,
+                &oid[0..10],
+                tracking,
+                subject,
+                width = width
+            );
+        } else {
+            println!("{} {}", prefix, branch);
+        }
+    }
+}
+
+fn tracking_summary(oid: String, upstream: &str) -> String {
+    // How a branch compares to its upstream, ex: "ahead 3, behind 1", empty
+    // when they are at the same commit
+    let upstream_oid = data::get_ref(upstream.to_owned(), true).value;
+    if upstream_oid.is_empty() {
+        return "gone".to_owned();
+    }
+    let (ahead, behind) = base::get_ahead_behind(oid, upstream_oid);
+    let mut counts = vec![];
+    if ahead > 0 {
+        counts.push(format!("ahead {}", ahead));
+    }
+    if behind > 0 {
+        counts.push(format!("behind {}", behind));
+    }
+    counts.join(", ")
+}
+
+fn short_ref_name(reference: &str) -> String {
+    reference
+        .trim_start_matches("refs/heads/")
+        .trim_start_matches("refs/remotes/")
+        .trim_start_matches("refs/")
+        .to_owned()
+}
+
 fn k() {
     let mut dot = "digraph commits {\n".to_owned();
     let mut oids = VecDeque::new();
@@ -243,12 +944,14 @@ fn k() {
             oid,
             &oid[0..10]
         ));
-        if commit.parents[0] != "" {
-            println!("Parent: {}", commit.parents[0]);
-            dot.push_str(&format!("\"{}\" -> \"{}\"\n", oid, commit.parents[0]));
+        for parent in commit.parents.iter() {
+            if !parent.is_empty() {
+                println!("Parent: {}", parent);
+                dot.push_str(&format!("\"{}\" -> \"{}\"\n", oid, parent));
+            }
         }
     }
-    dot.push_str("}");
+    dot.push('}');
     println!("{}", dot);
 
     let mut child = Command::new("dot")
@@ -271,15 +974,47 @@ fn branch(matches: ArgMatches) {
     if let Some(cmd_matches) = matches.subcommand_matches("branch") {
         let name = cmd_matches.value_of("name").unwrap_or("").to_owned();
         let provided_ref = cmd_matches.value_of("start_point").unwrap().to_owned();
-        if name == "" {
-            let current = base::get_branch_name();
-            for branch in base::iter_branch_names() {
-                let prefix = if branch == current { "*" } else { " " };
-                println!("{} {}", prefix, branch);
+        let force_delete = cmd_matches.is_present("force-delete");
+        let force_move = cmd_matches.is_present("force-move");
+        if cmd_matches.is_present("set-upstream-to") || cmd_matches.is_present("unset-upstream") {
+            let branch = if !name.is_empty() {
+                name
+            } else {
+                base::get_branch_name()
+            };
+            assert!(!branch.is_empty(), "HEAD is detached, give a branch name");
+            let upstream = cmd_matches.value_of("set-upstream-to").unwrap_or("");
+            base::set_upstream(&branch, upstream);
+            if !upstream.is_empty() {
+                println!(
+                    "Branch {} set up to track {}",
+                    branch,
+                    short_ref_name(&base::get_upstream(&branch))
+                );
             }
+        } else if cmd_matches.is_present("delete") || force_delete {
+            assert!(!name.is_empty(), "Branch name required");
+            let oid = base::delete_branch(name.clone(), force_delete);
+            println!("Deleted branch {} (was {})", name, &oid[0..10]);
+        } else if cmd_matches.is_present("move") || force_move {
+            assert!(!name.is_empty(), "Branch name required");
+            // With a single name, the current branch is renamed
+            let (old_name, new_name) = if cmd_matches.occurrences_of("start_point") > 0 {
+                (name, provided_ref)
+            } else {
+                (base::get_branch_name(), name)
+            };
+            assert!(
+                !old_name.is_empty(),
+                "HEAD is detached, there is no branch to rename"
+            );
+            base::rename_branch(old_name.clone(), new_name.clone(), force_move);
+            println!("Renamed branch {} to {}", old_name, new_name);
+        } else if name.is_empty() {
+            list_branches(cmd_matches);
         } else {
             let oid = base::get_oid(provided_ref.clone());
-            base::create_branch(name.clone(), oid.clone());
+            base::create_branch(name.clone(), oid.clone(), cmd_matches.is_present("force"));
             println!("Branch {} created_at {}", name, oid);
         }
     }
@@ -288,24 +1023,101 @@ fn branch(matches: ArgMatches) {
 fn status() {
     let branch = base::get_branch_name();
     let head = base::get_oid("@".to_owned());
-    if branch != "".to_owned() {
+    if !branch.is_empty() {
         println!("On branch {}", branch);
+        let upstream = base::get_upstream(&branch);
+        if !upstream.is_empty() && !head.is_empty() {
+            let name = short_ref_name(&upstream);
+            match tracking_summary(head.clone(), &upstream).as_str() {
+                "" => println!("Your branch is up to date with {}", name),
+                "gone" => println!("Your branch is tracking {}, which is gone", name),
+                summary => println!("Your branch is {} relative to {}", summary, name),
+            }
+        }
     } else {
-        println!("HEAD detached at {}", &head[1..10])
+        println!(
+            "{}",
+            color::paint(
+                "status.nobranch",
+                &format!("HEAD detached at {}", &head[1..10])
+            )
+        )
     }
 
     let merge_head = data::get_ref("MERGE_HEAD".to_owned(), true).value;
-    if merge_head != "".to_owned() {
-        println!("Merging with {}", &merge_head[1..10]);
+    for oid in merge_head.lines() {
+        println!("Merging with {}", &oid[0..10]);
+    }
+    let (rebasing, onto) = rebase::get_rebase_status();
+    if !rebasing.is_empty() {
+        println!(
+            "You are currently rebasing {} onto {}",
+            rebasing.trim_start_matches("refs/heads/"),
+            &onto[0..10]
+        );
+    }
+    let (action, oid) = sequencer::get_current_action();
+    if action == "CHERRY_PICK_HEAD" {
+        println!("You are currently cherry-picking commit {}", &oid[0..10]);
+    } else if action == "REVERT_HEAD" {
+        println!("You are currently reverting commit {}", &oid[0..10]);
+    }
+
+    let head_tree = if !head.is_empty() {
+        base::get_commit(head).tree
+    } else {
+        "".to_owned()
+    };
+    let index = data::get_index();
+    let conflicts = data::get_index_conflicts();
+
+    if !conflicts.is_empty() {
+        println!("{}", color::paint("status.header", "Unmerged paths:\n"));
+        let mut paths: Vec<&String> = conflicts.keys().collect();
+        paths.sort();
+        for path in paths {
+            let line = format!("{:>16}: {}", unmerged_state(&conflicts[path]), path);
+            println!("{}", color::paint("status.unmerged", &line));
+        }
+        println!();
+    }
+
+    // Unmerged paths are not part of the index yet, so leave them out of the
+    // other sections
+    let mut head_tree = base::get_tree(head_tree, "".to_owned());
+    let mut working_tree = base::get_working_tree();
+    head_tree.retain(|path, _| !conflicts.contains_key(path));
+    working_tree.retain(|path, _| !conflicts.contains_key(path));
+
+    println!(
+        "{}",
+        color::paint("status.header", "Changes to be committed:\n")
+    );
+    for (path, action) in diff::changed_files(head_tree, index.clone()) {
+        let line = format!("{:>12}: {}", action, path);
+        println!("{}", color::paint("status.added", &line));
     }
 
-    println!("Changes to be committed:\n");
-    let head_commit = base::get_commit(head);
-    for (path, action) in diff::changed_files(
-        base::get_tree(head_commit.tree, "".to_owned()),
-        base::get_working_tree(),
-    ) {
-        println!("{:>12}: {}", action, path);
+    println!(
+        "{}",
+        color::paint("status.header", "\nChanges not staged for commit:\n")
+    );
+    for (path, action) in diff::changed_files(index, working_tree) {
+        let line = format!("{:>12}: {}", action, path);
+        println!("{}", color::paint("status.changed", &line));
+    }
+}
+
+fn unmerged_state(stages: &[String]) -> &'static str {
+    let present: Vec<bool> = stages.iter().map(|oid| !oid.is_empty()).collect();
+    match (present[0], present[1], present[2]) {
+        (true, true, true) => "both modified",
+        (false, true, true) => "both added",
+        (true, true, false) => "deleted by them",
+        (true, false, true) => "deleted by us",
+        (false, true, false) => "added by us",
+        (false, false, true) => "added by them",
+        _ => "both deleted",
     }
 }
 
@@ -321,7 +1133,7 @@ fn show(matches: ArgMatches) {
         let oid = base::get_oid(cmd_matches.value_of("oid").unwrap().to_owned());
         let commit = base::get_commit(oid.clone());
         let refs: HashMap<String, Vec<String>> = HashMap::new();
-        let parent_tree = if commit.parents[0] != "".to_owned() {
+        let parent_tree = if !commit.parents[0].is_empty() {
             base::get_commit(commit.parents[0].clone()).tree
         } else {
             "".to_owned()
@@ -332,26 +1144,110 @@ fn show(matches: ArgMatches) {
             base::get_tree(parent_tree, "".to_owned()),
             base::get_tree(commit.tree, "".to_owned()),
         );
-        println!("{}", result);
+        println!("{}", color::diff(&result));
     }
 }
 
 fn difference(matches: ArgMatches) {
     if let Some(cmd_matches) = matches.subcommand_matches("diff") {
-        let oid = base::get_oid(cmd_matches.value_of("commit").unwrap().to_owned());
-        let commit = base::get_commit(oid);
+        let mut commits: Vec<String> = match cmd_matches.values_of("commits") {
+            Some(values) => values.map(|v| v.to_owned()).collect(),
+            None => vec![],
+        };
+        let paths: Vec<String> = match cmd_matches.values_of("paths") {
+            Some(values) => values.map(|v| v.to_owned()).collect(),
+            None => vec![],
+        };
+
+        // A single "A..B" or "A...B" argument is the same as comparing two commits
+        assert!(
+            commits.len() < 2 || !commits.iter().any(|commit| commit.contains("..")),
+            "A range cannot be combined with other commits"
+        );
+        if commits.len() == 1 && commits[0].contains("..") {
+            let range = commits.remove(0);
+            let symmetric = range.contains("...");
+            let separator = if symmetric { "..." } else { ".." };
+            let sides: Vec<&str> = range.splitn(2, separator).collect();
+            let from = if sides[0].is_empty() { "@" } else { sides[0] };
+            let to = if sides[1].is_empty() { "@" } else { sides[1] };
+            if symmetric {
+                let base = base::get_merge_base(
+                    base::get_oid(from.to_owned()),
+                    base::get_oid(to.to_owned()),
+                );
+                assert!(
+                    !base.is_empty(),
+                    "{} and {} have no common ancestor",
+                    from,
+                    to
+                );
+                commits.push(base);
+            } else {
+                commits.push(from.to_owned());
+            }
+            commits.push(to.to_owned());
+        }
+
+        let (t_from, t_to) = if commits.len() == 2 {
+            (
+                base::get_commit_tree(commits[0].clone()),
+                base::get_commit_tree(commits[1].clone()),
+            )
+        } else if commits.len() > 2 {
+            panic!("Too many commits provided to diff");
+        } else if cmd_matches.is_present("cached") {
+            // Before the first commit everything in the index is new
+            let head = data::get_ref("HEAD".to_owned(), true).value;
+            let tree = match commits.pop() {
+                Some(commit) => base::get_commit_tree(commit),
+                None if head.is_empty() => HashMap::new(),
+                None => base::get_commit_tree(head),
+            };
+            (tree, data::get_index())
+        } else if commits.len() == 1 {
+            base::assert_work_tree("diff");
+            (
+                base::get_commit_tree(commits[0].clone()),
+                base::get_working_tree(),
+            )
+        } else {
+            base::assert_work_tree("diff");
+            (data::get_index(), base::get_working_tree())
+        };
+
         let result = diff::diff_trees(
-            base::get_tree(commit.tree, "".to_owned()),
-            base::get_working_tree(),
+            diff::filter_tree(t_from, &paths),
+            diff::filter_tree(t_to, &paths),
         );
-        println!("{}", result);
+        println!("{}", color::diff(&result));
     }
 }
 
 fn merge(matches: ArgMatches) {
     if let Some(cmd_matches) = matches.subcommand_matches("merge") {
-        let oid = base::get_oid(cmd_matches.value_of("commit").unwrap().to_owned());
-        base::merge(oid);
+        if cmd_matches.is_present("abort") {
+            base::merge_abort();
+        } else if cmd_matches.is_present("continue") {
+            println!("{}", base::merge_continue());
+        } else {
+            let options = base::MergeOptions {
+                strategy: cmd_matches.value_of("strategy").unwrap().to_owned(),
+                favor: cmd_matches
+                    .value_of("strategy-option")
+                    .unwrap_or("")
+                    .to_owned(),
+                no_ff: cmd_matches.is_present("no-ff"),
+                ff_only: cmd_matches.is_present("ff-only"),
+                squash: cmd_matches.is_present("squash"),
+            };
+            let names: Vec<String> = cmd_matches
+                .values_of("commit")
+                .unwrap()
+                .map(|name| name.to_owned())
+                .collect();
+            base::merge(names, &options);
+        }
     }
 }
 
@@ -359,7 +1255,370 @@ fn merge_base(matches: ArgMatches) {
     if let Some(cmd_matches) = matches.subcommand_matches("merge-base") {
         let commit1 = base::get_oid(cmd_matches.value_of("commit1").unwrap().to_owned());
         let commit2 = base::get_oid(cmd_matches.value_of("commit2").unwrap().to_owned());
-        println!("{}", base::get_merge_base(commit1, commit2));
+        if cmd_matches.is_present("all") {
+            for oid in base::get_merge_bases(commit1, commit2) {
+                println!("{}", oid);
+            }
+        } else {
+            println!("{}", base::get_merge_base(commit1, commit2));
+        }
+    }
+}
+
+fn cherry_pick(matches: ArgMatches) {
+    if let Some(cmd_matches) = matches.subcommand_matches("cherry-pick") {
+        if cmd_matches.is_present("abort") {
+            sequencer::sequencer_abort();
+        } else if cmd_matches.is_present("continue") {
+            sequencer::sequencer_continue();
+        } else {
+            let options = sequencer::SequencerOptions {
+                record_origin: cmd_matches.is_present("record-origin"),
+                no_commit: cmd_matches.is_present("no-commit"),
+                mainline: cmd_matches
+                    .value_of("mainline")
+                    .unwrap_or("0")
+                    .parse()
+                    .expect("The mainline must be a parent number"),
+            };
+            let names: Vec<String> = cmd_matches
+                .values_of("commits")
+                .unwrap()
+                .map(|name| name.to_owned())
+                .collect();
+            sequencer::cherry_pick(names, options);
+        }
+    }
+}
+
+fn revert(matches: ArgMatches) {
+    if let Some(cmd_matches) = matches.subcommand_matches("revert") {
+        if cmd_matches.is_present("abort") {
+            sequencer::sequencer_abort();
+        } else if cmd_matches.is_present("continue") {
+            sequencer::sequencer_continue();
+        } else {
+            let options = sequencer::SequencerOptions {
+                record_origin: false,
+                no_commit: cmd_matches.is_present("no-commit"),
+                mainline: cmd_matches
+                    .value_of("mainline")
+                    .unwrap_or("0")
+                    .parse()
+                    .expect("The mainline must be a parent number"),
+            };
+            let names: Vec<String> = cmd_matches
+                .values_of("commits")
+                .unwrap()
+                .map(|name| name.to_owned())
+                .collect();
+            sequencer::revert(names, options);
+        }
+    }
+}
+
+fn rebase(matches: ArgMatches) {
+    if let Some(cmd_matches) = matches.subcommand_matches("rebase") {
+        if cmd_matches.is_present("abort") {
+            rebase::rebase_abort();
+        } else if cmd_matches.is_present("continue") {
+            rebase::rebase_continue();
+        } else if cmd_matches.is_present("skip") {
+            rebase::rebase_skip();
+        } else {
+            rebase::rebase(
+                cmd_matches.value_of("upstream").unwrap().to_owned(),
+                cmd_matches.value_of("onto").unwrap_or("").to_owned(),
+                cmd_matches.is_present("interactive"),
+            );
+        }
+    }
+}
+
+fn stash(matches: ArgMatches) {
+    if let Some(cmd_matches) = matches.subcommand_matches("stash") {
+        let (action, action_matches) = cmd_matches.subcommand();
+        let name = match action_matches {
+            Some(action_matches) => action_matches.value_of("stash").unwrap_or(""),
+            None => "",
+        }
+        .to_owned();
+        match action {
+            "pop" => stash::pop(name),
+            "apply" => {
+                stash::apply(name);
+            }
+            "drop" => stash::drop(name),
+            "clear" => stash::clear(),
+            "list" => {
+                for (name, message) in stash::list() {
+                    println!("{}: {}", name, message);
+                }
+            }
+            "show" => {
+                let (_, oid) = stash::get_stash(&name);
+                let commit = base::get_commit(oid);
+                let t_from = base::get_tree(
+                    base::get_commit(commit.parents[0].clone()).tree,
+                    "".to_owned(),
+                );
+                let t_to = base::get_tree(commit.tree, "".to_owned());
+                if action_matches.unwrap().is_present("patch") {
+                    println!("{}", color::diff(&diff::diff_trees(t_from, t_to)));
+                } else {
+                    for (path, action) in diff::changed_files(t_from, t_to) {
+                        println!("{:>12}: {}", action, path);
+                    }
+                }
+            }
+            _ => {
+                // "stash" alone is the same as "stash push"
+                let push_matches = action_matches.unwrap_or(cmd_matches);
+                let options = stash::StashOptions {
+                    message: push_matches.value_of("message").unwrap_or("").to_owned(),
+                    include_untracked: push_matches.is_present("include-untracked"),
+                    keep_index: push_matches.is_present("keep-index"),
+                };
+                stash::push(&options);
+            }
+        }
+    }
+}
+
+fn reflog(matches: ArgMatches) {
+    if let Some(cmd_matches) = matches.subcommand_matches("reflog") {
+        let name = cmd_matches.value_of("ref").unwrap();
+        let reference = base::get_ref_name(name);
+        assert!(!reference.is_empty(), "Unknown ref {}", name);
+        for (i, entry) in data::read_reflog(&reference).iter().rev().enumerate() {
+            println!(
+                "{} {}@{{{}}}: {}",
+                color::paint("diff.commit", &entry.new[0..7]),
+                name,
+                i,
+                entry.message
+            );
+        }
+    }
+}
+
+fn rev_parse(matches: ArgMatches) {
+    if let Some(cmd_matches) = matches.subcommand_matches("rev-parse") {
+        // Each argument is printed in turn, a range as "B ^A" like in Git
+        for revision in cmd_matches.values_of("revision").unwrap() {
+            let (include, exclude) = base::get_revision_range(vec![revision.to_owned()]);
+            for oid in include {
+                println!("{}", oid);
+            }
+            for oid in exclude {
+                println!("^{}", oid);
+            }
+        }
+    }
+}
+
+fn signing_key(matches: ArgMatches) {
+    if let Some(cmd_matches) = matches.subcommand_matches("signing-key") {
+        if let Some(values) = cmd_matches.values_of("trust") {
+            let values: Vec<&str> = values.collect();
+            sign::trust_key(values[0], values[1]);
+        } else if cmd_matches.is_present("generate") {
+            println!("{}", sign::generate_key());
+        } else {
+            println!("{}", sign::get_public_key());
+        }
+    }
+}
+
+fn verify(matches: ArgMatches, command: &str) {
+    if let Some(cmd_matches) = matches.subcommand_matches(command) {
+        let object_type = if command == "verify-tag" {
+            "tag"
+        } else {
+            "commit"
+        };
+        let mut all_good = true;
+        for name in cmd_matches.values_of(object_type).unwrap() {
+            // Tags are checked themselves, not the commit they point to
+            let oid = if object_type == "tag" {
+                base::get_object_oid(format!("{}^{{tag}}", name))
+            } else {
+                base::get_oid(name.to_owned())
+            };
+            let (good, description) = sign::verify(&oid, object_type);
+            println!("{}: {}", name, description);
+            all_good = all_good && good;
+        }
+        assert!(all_good, "Signature verification failed");
+    }
+}
+
+fn pack_refs(matches: ArgMatches) {
+    if let Some(cmd_matches) = matches.subcommand_matches("pack-refs") {
+        data::pack_refs(cmd_matches.is_present("all"));
+    }
+}
+
+fn stash_push_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("message")
+            .short("m")
+            .long("message")
+            .takes_value(true),
+        Arg::with_name("include-untracked")
+            .short("u")
+            .long("include-untracked")
+            .help("Also stash the untracked files"),
+        Arg::with_name("keep-index")
+            .short("k")
+            .long("keep-index")
+            .help("Leave the staged changes in place"),
+    ]
+}
+
+fn remote(matches: ArgMatches) {
+    if let Some(cmd_matches) = matches.subcommand_matches("remote") {
+        match cmd_matches.subcommand() {
+            ("add", Some(sub_matches)) => remote::add_remote(
+                sub_matches.value_of("name").unwrap(),
+                sub_matches.value_of("url").unwrap(),
+            ),
+            ("remove", Some(sub_matches)) => {
+                remote::remove_remote(sub_matches.value_of("name").unwrap())
+            }
+            ("rename", Some(sub_matches)) => remote::rename_remote(
+                sub_matches.value_of("old").unwrap(),
+                sub_matches.value_of("new").unwrap(),
+            ),
+            ("set-url", Some(sub_matches)) => remote::set_url(
+                sub_matches.value_of("name").unwrap(),
+                sub_matches.value_of("url").unwrap(),
+            ),
+            _ => {
+                for (name, url) in remote::list_remotes() {
+                    if cmd_matches.is_present("verbose") {
+                        println!("{}\t{}", name, url);
+                    } else {
+                        println!("{}", name);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn clone(matches: ArgMatches) {
+    if let Some(cmd_matches) = matches.subcommand_matches("clone") {
+        remote::clone(
+            cmd_matches.value_of("source").unwrap().to_owned(),
+            cmd_matches.value_of("directory").unwrap_or("").to_owned(),
+            cmd_matches.is_present("bare"),
+            cmd_matches.value_of("branch").unwrap_or("").to_owned(),
+        );
+    }
+}
+
+fn pull(matches: ArgMatches) {
+    if let Some(cmd_matches) = matches.subcommand_matches("pull") {
+        let rebase =
+            cmd_matches.is_present("rebase") || data::get_config_value("pull.rebase") == "true";
+        assert!(
+            !(rebase && cmd_matches.is_present("ff-only")),
+            "--ff-only cannot be used when pull.rebase is true, unset it to merge instead"
+        );
+
+        // Without arguments, pull the upstream of the current branch
+        let current = base::get_branch_name();
+        let upstream = base::get_upstream(&current);
+        let (upstream_remote, upstream_branch) = remote::get_tracked_branch(&upstream);
+        let remote_name = cmd_matches
+            .value_of("remote")
+            .map(|name| name.to_owned())
+            .unwrap_or(upstream_remote.clone());
+        if remote_name.is_empty() {
+            assert!(!current.is_empty(), "No remote given and HEAD is detached");
+            assert!(
+                !upstream.is_empty(),
+                "No remote given and {} has no upstream, set one with branch -u",
+                current
+            );
+            let local = upstream.trim_start_matches("refs/heads/");
+            panic!(
+                "{} tracks the local branch {}, there is nothing to fetch: use merge {} or rebase {}",
+                current, local, local, local
+            );
+        }
+        // The upstream branch only applies to its own remote, any other one
+        // is pulled from its HEAD
+        let branch = match cmd_matches.value_of("branch") {
+            Some(name) => name.to_owned(),
+            None if remote_name == upstream_remote => upstream_branch,
+            None => "".to_owned(),
+        };
+
+        let refs = remote::fetch(remote_name.clone());
+        let (name, oid) = if !branch.is_empty() {
+            let oid = refs.get(&format!("refs/heads/{}", branch)).cloned();
+            let oid =
+                oid.unwrap_or_else(|| panic!("Branch {} not found in {}", branch, remote_name));
+            (format!("{}/{}", remote_name, branch), oid)
+        } else {
+            let oid = refs.get("HEAD").cloned().unwrap_or("".to_owned());
+            (remote_name.clone(), oid)
+        };
+        assert!(!oid.is_empty(), "{} has no commits to pull", name);
+
+        let head = data::get_ref("HEAD".to_owned(), true).value;
+        if head.is_empty() {
+            // Nothing to integrate with yet, take the remote branch as it is.
+            // Files that are already there (untracked or staged) are kept.
+            let files = base::get_tree(base::get_commit(oid.clone()).tree, "".to_owned());
+            let mut index = data::get_index();
+            let mut existing: Vec<&String> = files
+                .keys()
+                .filter(|path| index.contains_key(*path) || fs::metadata(path).is_ok())
+                .collect();
+            existing.sort();
+            assert!(
+                existing.is_empty(),
+                "These files would be overwritten by pull, move or remove them first: {:?}",
+                existing
+            );
+            base::update_working_files(&HashMap::new(), &files);
+            index.extend(files);
+            data::set_index(index);
+            data::update_ref(
+                "HEAD".to_owned(),
+                data::RefValue {
+                    symbolic: false,
+                    value: oid,
+                },
+                true,
+                &format!("pull: from {}", name),
+            );
+            return;
+        }
+
+        if rebase {
+            rebase::rebase(oid, "".to_owned(), false);
+        } else {
+            let options = base::MergeOptions {
+                strategy: "recursive".to_owned(),
+                favor: "".to_owned(),
+                no_ff: false,
+                ff_only: cmd_matches.is_present("ff-only"),
+                squash: false,
+            };
+            // Merge the remote-tracking branch when it is up to date, so it
+            // is named in the merge message
+            let reference = base::get_ref_name(&name);
+            let target = if !reference.is_empty() && data::get_ref(reference, true).value == oid {
+                name
+            } else {
+                oid
+            };
+            base::merge(vec![target], &options);
+        }
     }
 }
 
@@ -385,14 +1644,46 @@ fn add(matches: ArgMatches) {
     }
 }
 
+fn config(matches: ArgMatches) {
+    if let Some(cmd_matches) = matches.subcommand_matches("config") {
+        let mut values = data::get_config();
+        if cmd_matches.is_present("list") {
+            let mut keys: Vec<&String> = values.keys().collect();
+            keys.sort();
+            for key in keys {
+                println!("{}={}", key, values[key]);
+            }
+            return;
+        }
+
+        let key = cmd_matches.value_of("key").expect("A key is required");
+        if cmd_matches.is_present("unset") {
+            values.remove(key);
+            data::set_config(values);
+        } else if let Some(value) = cmd_matches.value_of("value") {
+            values.insert(key.to_owned(), value.to_owned());
+            data::set_config(values);
+        } else if let Some(value) = values.get(key) {
+            println!("{}", value);
+        }
+    }
+}
+
 fn print_commit(oid: String, commit: &base::Commit, mut refs: HashMap<String, Vec<String>>) {
     let ref_str = if refs.contains_key(&oid) {
-        refs.get_mut(&oid).unwrap().join(", ")
+        let names: Vec<String> = refs
+            .get_mut(&oid)
+            .unwrap()
+            .iter()
+            .map(|name| color::decoration(name))
+            .collect();
+        names.join(", ")
     } else {
         "".to_owned()
     };
 
-    println!("commit {} {}", oid, ref_str);
+    let header = format!("commit {}", oid);
+    println!("{} {}", color::paint("diff.commit", &header), ref_str);
     println!("{}", commit.message);
-    println!("");
+    println!();
 }
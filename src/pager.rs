@@ -0,0 +1,62 @@
+use std::env;
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::panic;
+use std::process::{Child, Command, Stdio};
+use std::sync::Mutex;
+
+static DEFAULT_PAGER: &str = "less -FRX";
+static PAGER: Mutex<Option<Child>> = Mutex::new(None);
+
+pub fn setup() {
+    // Sends everything printed to stdout through $PAGER, but only when a
+    // person is looking at the terminal.
+    if unsafe { libc::isatty(libc::STDOUT_FILENO) } != 1 {
+        return;
+    }
+
+    let pager = env::var("PAGER").unwrap_or(DEFAULT_PAGER.to_owned());
+    if pager.is_empty() || pager == "cat" {
+        return;
+    }
+
+    let child = Command::new("sh")
+        .arg("-c")
+        .arg(pager)
+        .stdin(Stdio::piped())
+        .spawn();
+
+    if let Ok(child) = child {
+        let pager_stdin = child.stdin.as_ref().unwrap().as_raw_fd();
+        unsafe {
+            libc::dup2(pager_stdin, libc::STDOUT_FILENO);
+            // Quitting the pager early should end rgit quietly
+            libc::signal(libc::SIGPIPE, libc::SIG_DFL);
+        }
+        *PAGER.lock().unwrap() = Some(child);
+
+        // A failing command has to wait for the pager too, and its error
+        // is printed once the pager has given the terminal back
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            finish();
+            default_hook(info);
+        }));
+    }
+}
+
+pub fn finish() {
+    let pager = PAGER
+        .lock()
+        .unwrap_or_else(|error| error.into_inner())
+        .take();
+    if let Some(mut child) = pager {
+        let _ = io::stdout().flush();
+        // Close both ends we own, so the pager sees the end of the input
+        drop(child.stdin.take());
+        unsafe {
+            libc::close(libc::STDOUT_FILENO);
+        }
+        let _ = child.wait();
+    }
+}
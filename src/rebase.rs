@@ -0,0 +1,463 @@
+// Replays the commits of the current branch on top of another commit. The
+// progress is kept in .rgit/rebase-merge/, so a rebase that stops because of
+// a conflict (or an "edit" step) can be resumed with --continue, moved past
+// with --skip or undone with --abort.
+
+use std::collections::{HashSet, VecDeque};
+use std::env;
+use std::process::Command;
+
+#[path = "data.rs"]
+#[allow(dead_code)]
+mod data;
+
+#[path = "base.rs"]
+#[allow(dead_code)]
+mod base;
+
+#[path = "sequencer.rs"]
+#[allow(dead_code)]
+mod sequencer;
+
+static STATE_DIR: &str = "rebase-merge";
+
+static TODO_HELP: &str = "
+# Commands:
+# p, pick <commit> = use commit
+# r, reword <commit> = use commit, but edit the commit message
+# e, edit <commit> = use commit, but stop for amending
+# s, squash <commit> = use commit, but meld into previous commit
+# f, fixup <commit> = like \"squash\", but discard this commit's message
+# d, drop <commit> = remove commit
+#
+# These lines can be re-ordered; they are executed from top to bottom.
+# If you remove a line here THAT COMMIT WILL BE LOST.
+";
+
+pub fn rebase(upstream: String, onto: String, interactive: bool) {
+    assert!(
+        !is_in_progress(),
+        "A rebase is already in progress, use --continue, --skip or --abort"
+    );
+    let head = data::get_ref("HEAD".to_owned(), true).value;
+    assert!(!head.is_empty(), "There are no commits yet");
+    assert_clean_tree(&head);
+
+    let upstream = base::get_oid(upstream);
+    let onto = if !onto.is_empty() {
+        base::get_oid(onto)
+    } else {
+        upstream.clone()
+    };
+
+    let todo = get_commits_to_replay(&head, &upstream);
+    if todo.is_empty() && base::is_ancestor_of(head.clone(), onto.clone()) {
+        println!("Current branch is up to date.");
+        return;
+    }
+
+    let head_ref = data::get_ref("HEAD".to_owned(), false);
+    let head_name = if head_ref.symbolic {
+        head_ref.value
+    } else {
+        "detached HEAD".to_owned()
+    };
+    write_state("head-name", &head_name);
+    write_state("orig-head", &head);
+    write_state("onto", &onto);
+    write_state("done", "");
+
+    let mut lines: Vec<String> = todo
+        .iter()
+        .map(|oid| format!("pick {} {}", oid, get_subject(oid)))
+        .collect();
+    if interactive {
+        write_state("interactive", "");
+        let content = format!("{}\n{}", lines.join("\n"), TODO_HELP);
+        write_state("git-rebase-todo", &content);
+        launch_editor(&format!("{}/git-rebase-todo", STATE_DIR));
+        lines = parse_todo(&read_state("git-rebase-todo"));
+        if lines.is_empty() {
+            data::remove_rgit_file(STATE_DIR);
+            println!("Nothing to do");
+            return;
+        }
+    }
+    write_state("git-rebase-todo", &lines.join("\n"));
+
+    // Work on a detached HEAD, the branch is only moved once everything is done
+    base::restore_tree(base::get_commit(onto.clone()).tree);
+    set_head(&onto, &format!("rebase (start): checkout {}", onto));
+    run();
+}
+
+pub fn rebase_continue() {
+    assert!(is_in_progress(), "There is no rebase in progress");
+    assert!(
+        data::get_index_conflicts().is_empty(),
+        "Fix the conflicts and run add on them before continuing"
+    );
+    assert!(
+        !has_unstaged_changes(),
+        "Cannot continue: you have unstaged changes, add them first"
+    );
+
+    let action = read_state("current-action");
+    if !action.is_empty() {
+        // Finish the step that stopped because of a conflict
+        let message = data::read_rgit_file("MERGE_MSG");
+        data::remove_rgit_file("MERGE_MSG");
+        remove_state("current-action");
+        commit_step(&action, &message);
+        if action == "edit" {
+            // Like an edit step without conflicts, stop to let it be amended
+            let done = read_state("done");
+            let line = done.lines().last().unwrap_or("");
+            stop_for_edit(line.split(' ').nth(1).unwrap_or(""));
+            return;
+        }
+    } else if !read_state("amend").is_empty() {
+        // The "edit" step, changes staged in the meantime go into that commit
+        let head = data::get_ref("HEAD".to_owned(), true).value;
+        let commit = base::get_commit(head.clone());
+        if base::write_tree_from(&data::get_index()) != commit.tree {
+            amend_head(commit.message.trim_end());
+        }
+    }
+    remove_state("amend");
+    run();
+}
+
+pub fn rebase_skip() {
+    assert!(is_in_progress(), "There is no rebase in progress");
+    // Throw away whatever the current step left in the index and working tree
+    let head = data::get_ref("HEAD".to_owned(), true).value;
+    base::restore_tree(base::get_commit(head).tree);
+    data::remove_rgit_file("MERGE_MSG");
+    remove_state("current-action");
+    remove_state("amend");
+    run();
+}
+
+pub fn rebase_abort() {
+    assert!(is_in_progress(), "There is no rebase in progress");
+    let orig_head = read_state("orig-head");
+    base::restore_tree(base::get_commit(orig_head.clone()).tree);
+    data::remove_rgit_file("MERGE_MSG");
+    restore_head(&orig_head, "rebase (abort)");
+    data::remove_rgit_file(STATE_DIR);
+}
+
+pub fn get_rebase_status() -> (String, String) {
+    // The branch being rebased and the commit it is going onto
+    if !is_in_progress() {
+        return ("".to_owned(), "".to_owned());
+    }
+    (read_state("head-name"), read_state("onto"))
+}
+
+fn run() {
+    loop {
+        let mut todo: Vec<String> = read_state("git-rebase-todo")
+            .lines()
+            .map(|line| line.to_owned())
+            .collect();
+        if todo.is_empty() {
+            break;
+        }
+        let line = todo.remove(0);
+        write_state("git-rebase-todo", &todo.join("\n"));
+        write_state("done", &format!("{}{}\n", read_state("done"), line));
+
+        let items: Vec<&str> = line.splitn(3, " ").collect();
+        let action = items[0];
+        let oid = items[1].to_owned();
+        if action == "drop" {
+            continue;
+        }
+
+        let head = data::get_ref("HEAD".to_owned(), true).value;
+        let commit = base::get_commit(oid.clone());
+        if (action == "pick" || action == "edit") && commit.parents[0] == head {
+            // Nothing changed below this commit, so it can be reused as it is
+            base::restore_tree(commit.tree);
+            set_head(&oid, &format!("rebase (pick): {}", get_subject(&oid)));
+        } else {
+            let options = sequencer::SequencerOptions {
+                record_origin: false,
+                no_commit: false,
+                mainline: 0,
+            };
+            let (conflicts, message) = sequencer::apply("pick", &oid, &options);
+            let message = get_step_message(action, &message);
+            if !conflicts.is_empty() {
+                data::write_rgit_file("MERGE_MSG", &message);
+                write_state("current-action", action);
+                for (_, conflict) in conflicts.iter() {
+                    println!("{}", conflict);
+                }
+                println!("Could not apply {}... {}", &oid[0..7], get_subject(&oid));
+                println!("Fix the conflicts, run add on them and then rebase --continue");
+                println!("(or rebase --skip to drop this commit, rebase --abort to give up)");
+                return;
+            }
+            commit_step(action, &message);
+        }
+
+        if action == "edit" {
+            stop_for_edit(&oid);
+            return;
+        }
+    }
+    finish();
+}
+
+fn stop_for_edit(oid: &str) {
+    write_state("amend", oid);
+    println!("Stopped at {}... {}", &oid[0..7], get_subject(oid));
+    println!("Make your changes, run add on them and then rebase --continue");
+}
+
+fn commit_step(action: &str, message: &str) {
+    // Records the changes of a step that has been applied to the index
+    match action {
+        "squash" | "fixup" => {
+            let message = if action == "squash" {
+                edit_message(message)
+            } else {
+                message.to_owned()
+            };
+            amend_head(&message);
+        }
+        "reword" => {
+            base::commit(&edit_message(message), false);
+        }
+        _ => {
+            base::commit(message, false);
+        }
+    };
+}
+
+fn get_step_message(action: &str, message: &str) -> String {
+    // Squashed commits are melded into the message of the previous one
+    if action != "squash" && action != "fixup" {
+        return message.to_owned();
+    }
+    let head = data::get_ref("HEAD".to_owned(), true).value;
+    let previous = base::get_commit(head).message.trim_end().to_owned();
+    if action == "fixup" {
+        return previous;
+    }
+    format!("{}\n\n{}", previous, message)
+}
+
+fn finish() {
+    let head = data::get_ref("HEAD".to_owned(), true).value;
+    let head_name = read_state("head-name");
+    data::update_ref(
+        "ORIG_HEAD".to_owned(),
+        data::RefValue {
+            symbolic: false,
+            value: read_state("orig-head"),
+        },
+        false,
+        "",
+    );
+    let reason = format!("rebase (finish): {} onto {}", head_name, read_state("onto"));
+    restore_head(&head, &reason);
+    data::remove_rgit_file(STATE_DIR);
+    println!("Successfully rebased and updated {}.", head_name);
+}
+
+fn restore_head(oid: &str, reason: &str) {
+    // Points the branch being rebased to the given commit and checks it out
+    let head_name = read_state("head-name");
+    if head_name.starts_with("refs/heads/") {
+        // Refuse to overwrite the branch if something else moved it meanwhile
+        let mut transaction = data::RefTransaction::new();
+        transaction.update(
+            head_name.clone(),
+            data::RefValue {
+                symbolic: false,
+                value: oid.to_owned(),
+            },
+            true,
+            Some(read_state("orig-head")),
+            reason,
+        );
+        transaction.update(
+            "HEAD".to_owned(),
+            data::RefValue {
+                symbolic: true,
+                value: head_name,
+            },
+            false,
+            None,
+            reason,
+        );
+        transaction.commit();
+    } else {
+        set_head(oid, reason);
+    }
+}
+
+fn get_commits_to_replay(head: &str, upstream: &str) -> Vec<String> {
+    // Commits reachable from HEAD but not from upstream, oldest first. Merge
+    // commits are left out, their changes come from the commits they merge.
+    let mut upstream_commits = VecDeque::new();
+    upstream_commits.push_back(upstream.to_owned());
+    let excluded: HashSet<String> = base::iter_commits_and_parents(upstream_commits)
+        .into_iter()
+        .collect();
+
+    let mut head_commits = VecDeque::new();
+    head_commits.push_back(head.to_owned());
+    let mut commits: Vec<String> = base::iter_commits_and_parents(head_commits)
+        .into_iter()
+        .filter(|oid| !excluded.contains(oid))
+        .filter(|oid| base::get_commit(oid.clone()).parents.len() == 1)
+        .collect();
+    commits.reverse();
+    commits
+}
+
+fn parse_todo(content: &str) -> Vec<String> {
+    let mut lines = vec![];
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("#") {
+            continue;
+        }
+        let items: Vec<&str> = line.splitn(3, " ").collect();
+        let action = match items[0] {
+            "p" | "pick" => "pick",
+            "r" | "reword" => "reword",
+            "e" | "edit" => "edit",
+            "s" | "squash" => "squash",
+            "f" | "fixup" => "fixup",
+            "d" | "drop" => "drop",
+            _ => panic!("Unknown rebase command: {}", items[0]),
+        };
+        assert!(
+            items.len() > 1,
+            "Missing commit in rebase command: {}",
+            line
+        );
+        let oid = base::get_oid(items[1].to_owned());
+        if action == "squash" || action == "fixup" {
+            assert!(
+                lines.iter().any(|l: &String| !l.starts_with("drop ")),
+                "Cannot {} without a previous commit",
+                action
+            );
+        }
+        lines.push(format!("{} {} {}", action, oid, get_subject(&oid)));
+    }
+    lines
+}
+
+fn edit_message(message: &str) -> String {
+    write_state("message", &format!("{}\n", message));
+    launch_editor(&format!("{}/message", STATE_DIR));
+    let content = read_state("message");
+    let edited: Vec<&str> = content
+        .lines()
+        .filter(|line| !line.starts_with("#"))
+        .collect();
+    let edited = edited.join("\n").trim().to_owned();
+    assert!(
+        !edited.is_empty(),
+        "Aborting commit due to empty commit message"
+    );
+    remove_state("message");
+    edited
+}
+
+fn launch_editor(file: &str) {
+    // Same lookup order as Git: $GIT_EDITOR, core.editor, $EDITOR and then vi
+    let mut editor = env::var("GIT_EDITOR").unwrap_or("".to_owned());
+    if editor.is_empty() {
+        editor = data::get_config_value("core.editor");
+    }
+    if editor.is_empty() {
+        editor = env::var("EDITOR").unwrap_or("vi".to_owned());
+    }
+
+    let path = data::get_rgit_file_path(file);
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", editor))
+        .arg(editor.clone())
+        .arg(path)
+        .status()
+        .expect("Cannot launch the editor");
+    assert!(status.success(), "The editor exited with an error");
+}
+
+fn amend_head(message: &str) {
+    // Replaces HEAD with a commit of the current index, keeping its parents
+    let head = data::get_ref("HEAD".to_owned(), true).value;
+    let mut commit = format!("tree {}\n", base::write_tree_from(&data::get_index()));
+    for parent in base::get_commit(head).parents {
+        if !parent.is_empty() {
+            commit += format!("parent {}\n", parent).as_str();
+        }
+    }
+    commit += format!("\n{}\n", message).as_str();
+    let oid = data::hash_object(&commit.into_bytes(), "commit".to_owned());
+    set_head(
+        &oid,
+        &format!("rebase (amend): {}", message.lines().next().unwrap_or("")),
+    );
+}
+
+fn assert_clean_tree(head: &str) {
+    assert!(
+        data::get_index_conflicts().is_empty()
+            && data::get_index()
+                == base::get_tree(base::get_commit(head.to_owned()).tree, "".to_owned())
+            && !has_unstaged_changes(),
+        "Cannot rebase: you have uncommitted changes, commit them first"
+    );
+}
+
+fn has_unstaged_changes() -> bool {
+    // Tracked files that differ from the index
+    let index = data::get_index();
+    let mut working_tree = base::get_working_tree();
+    working_tree.retain(|path, _| index.contains_key(path));
+    working_tree != index
+}
+
+fn get_subject(oid: &str) -> String {
+    let message = base::get_commit(oid.to_owned()).message;
+    message.lines().next().unwrap_or("").to_owned()
+}
+
+fn set_head(oid: &str, reason: &str) {
+    data::update_ref(
+        "HEAD".to_owned(),
+        data::RefValue {
+            symbolic: false,
+            value: oid.to_owned(),
+        },
+        false,
+        reason,
+    );
+}
+
+fn is_in_progress() -> bool {
+    !read_state("orig-head").is_empty()
+}
+
+fn read_state(name: &str) -> String {
+    data::read_rgit_file(&format!("{}/{}", STATE_DIR, name))
+}
+
+fn write_state(name: &str, content: &str) {
+    data::write_rgit_file(&format!("{}/{}", STATE_DIR, name), content);
+}
+
+fn remove_state(name: &str) {
+    data::remove_rgit_file(&format!("{}/{}", STATE_DIR, name));
+}
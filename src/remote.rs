@@ -1,60 +1,438 @@
+// Remotes are other repositories we fetch from and push to. Named remotes
+// are kept in the config, "remote.<name>.url" for where the repository is and
+// "remote.<name>.fetch" for the refspecs ("[+]<src>:<dst>", with "*" matching
+// any name) telling which remote refs are fetched and where they are stored,
+// "+refs/heads/*:refs/remotes/<name>/*" by default.
+
 use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::Path;
 
 #[path = "data.rs"]
+#[allow(dead_code)]
 mod data;
 
 #[path = "base.rs"]
+#[allow(dead_code)]
 mod base;
 
-static REMOTE_REFS_BASE: &'static str = "refs/heads/";
-static LOCAL_REFS_BASE: &'static str = "refs/remote/";
+static REMOTE_REFS_BASE: &str = "refs/heads/";
+static LOCAL_REFS_BASE: &str = "refs/remotes/";
+// Fetching a path that is not a named remote keeps storing its branches in
+// refs/remote/, where they always went before remotes had names
+static PATH_FETCH_REFSPEC: &str = "+refs/heads/*:refs/remote/*";
+
+pub fn add_remote(name: &str, url: &str) {
+    assert!(
+        !name.is_empty() && !name.contains(|c: char| c.is_whitespace() || c == '/' || c == ':'),
+        "{} is not a valid remote name",
+        name
+    );
+    assert!(get_url(name).is_empty(), "Remote {} already exists", name);
+    let mut values = data::get_config();
+    values.insert(format!("remote.{}.url", name), url.to_owned());
+    values.insert(format!("remote.{}.fetch", name), default_refspec(name));
+    data::set_config(values);
+}
+
+pub fn remove_remote(name: &str) {
+    // Also drops its remote-tracking refs and the upstreams pointing to them
+    assert!(!get_url(name).is_empty(), "No such remote {}", name);
+    let prefix = format!("{}{}/", LOCAL_REFS_BASE, name);
+    let mut transaction = data::RefTransaction::new();
+    for (reference, _) in data::iter_refs(&prefix, false) {
+        transaction.delete(reference, false, None);
+    }
+    transaction.commit();
+
+    let mut values = data::get_config();
+    values.retain(|key, value| {
+        !(key.starts_with(&format!("remote.{}.", name))
+            || key.ends_with(".upstream") && value.starts_with(&prefix))
+    });
+    data::set_config(values);
+}
+
+pub fn rename_remote(old_name: &str, new_name: &str) {
+    assert!(!get_url(old_name).is_empty(), "No such remote {}", old_name);
+    assert!(
+        get_url(new_name).is_empty(),
+        "Remote {} already exists",
+        new_name
+    );
+    let old_prefix = format!("{}{}/", LOCAL_REFS_BASE, old_name);
+    let new_prefix = format!("{}{}/", LOCAL_REFS_BASE, new_name);
+
+    let mut values = data::get_config();
+    let url = values.remove(&format!("remote.{}.url", old_name)).unwrap();
+    let mut refspecs = values
+        .remove(&format!("remote.{}.fetch", old_name))
+        .unwrap_or(default_refspec(old_name));
+    if refspecs == default_refspec(old_name) {
+        refspecs = default_refspec(new_name);
+    }
+    values.insert(format!("remote.{}.url", new_name), url);
+    values.insert(format!("remote.{}.fetch", new_name), refspecs);
+    for (key, value) in values.iter_mut() {
+        if key.ends_with(".upstream") && value.starts_with(&old_prefix) {
+            *value = value.replacen(&old_prefix, &new_prefix, 1);
+        }
+    }
+
+    let reason = format!("remote: renamed {} to {}", old_name, new_name);
+    let mut transaction = data::RefTransaction::new();
+    for (reference, value) in data::iter_refs(&old_prefix, false) {
+        transaction.delete(reference.clone(), false, Some(value.value.clone()));
+        transaction.update(
+            reference.replacen(&old_prefix, &new_prefix, 1),
+            value,
+            false,
+            None,
+            &reason,
+        );
+    }
+    transaction.commit();
+    data::set_config(values);
+}
+
+pub fn set_url(name: &str, url: &str) {
+    assert!(!get_url(name).is_empty(), "No such remote {}", name);
+    let mut values = data::get_config();
+    values.insert(format!("remote.{}.url", name), url.to_owned());
+    data::set_config(values);
+}
+
+pub fn list_remotes() -> Vec<(String, String)> {
+    // Names of the configured remotes along with their URL, sorted
+    let mut remotes: Vec<(String, String)> = data::get_config()
+        .into_iter()
+        .filter(|(key, _)| key.starts_with("remote.") && key.ends_with(".url"))
+        .map(|(key, url)| {
+            let name = key.trim_start_matches("remote.").trim_end_matches(".url");
+            (name.to_owned(), url)
+        })
+        .collect();
+    remotes.sort();
+    remotes
+}
+
+pub fn get_tracked_branch(upstream: &str) -> (String, String) {
+    // Remote and branch names of a remote-tracking ref, empty if it is not one
+    if !upstream.starts_with(LOCAL_REFS_BASE) {
+        return ("".to_owned(), "".to_owned());
+    }
+    let items: Vec<&str> = upstream[LOCAL_REFS_BASE.len()..].splitn(2, "/").collect();
+    if items.len() < 2 {
+        return ("".to_owned(), "".to_owned());
+    }
+    (items[0].to_owned(), items[1].to_owned())
+}
+
+pub fn get_url(name: &str) -> String {
+    data::get_config_value(&format!("remote.{}.url", name))
+}
+
+pub fn fetch(remote: String) -> HashMap<String, String> {
+    // A configured remote updates the refs its refspecs point to, a plain
+    // path uses PATH_FETCH_REFSPEC. The remote HEAD is stored in FETCH_HEAD.
+    // Returns all the refs of the remote.
+    let url = get_url(&remote);
+    let (path, refspecs) = if !url.is_empty() {
+        let refspecs = data::get_config_value(&format!("remote.{}.fetch", remote));
+        (url, refspecs)
+    } else {
+        (remote.clone(), PATH_FETCH_REFSPEC.to_owned())
+    };
 
-pub fn fetch(path: String) {
     // Get refs from server
-    let refs = get_remote_refs(path.clone(), REMOTE_REFS_BASE);
+    let refs = get_remote_refs(path.clone(), "");
+    let mut updates = vec![];
+    for refspec in refspecs.split_whitespace() {
+        let forced = refspec.starts_with("+");
+        let items: Vec<&str> = refspec.trim_start_matches("+").splitn(2, ":").collect();
+        assert!(items.len() == 2, "Invalid refspec {}", refspec);
+        let mut names: Vec<&String> = refs.keys().collect();
+        names.sort();
+        for name in names {
+            if let Some(local_name) = map_refspec(items[0], items[1], name) {
+                updates.push((name.clone(), local_name, forced));
+            }
+        }
+    }
+    let remote_head = refs.get("HEAD").cloned().unwrap_or("".to_owned());
 
-    let commit_oids: Vec<&String> = refs.values().collect();
+    let mut commit_oids: Vec<&String> = updates.iter().map(|update| &refs[&update.0]).collect();
+    if !remote_head.is_empty() {
+        commit_oids.push(&remote_head);
+    }
     base::copy_objects_in_commits_and_parents(commit_oids, path.clone(), false);
 
-    // Update local refs to match server
-    for (remote_name, value) in refs.iter() {
-        let refname = remote_name.trim_start_matches(REMOTE_REFS_BASE);
+    // Update local refs to match server, all of them or none. Refs that
+    // would lose commits are only moved by refspecs starting with "+".
+    let mut transaction = data::RefTransaction::new();
+    for (remote_name, local_name, forced) in updates {
+        let value = refs[&remote_name].clone();
+        let current = data::get_ref(local_name.clone(), false).value;
+        if current == value {
+            continue;
+        }
+        let reason = if current.is_empty() {
+            "fetch: storing head"
+        } else if base::is_ancestor_of(value.clone(), current.clone()) {
+            "fetch: fast-forward"
+        } else if forced {
+            "fetch: forced-update"
+        } else {
+            println!(
+                "! [rejected] {} -> {} (non-fast-forward)",
+                remote_name, local_name
+            );
+            continue;
+        };
+        println!("{} -> {}", remote_name, local_name);
+        transaction.update(
+            local_name,
+            data::RefValue {
+                symbolic: false,
+                value,
+            },
+            false,
+            Some(current),
+            reason,
+        );
+    }
+    if !remote_head.is_empty() {
+        transaction.update(
+            "FETCH_HEAD".to_owned(),
+            data::RefValue {
+                symbolic: false,
+                value: remote_head,
+            },
+            false,
+            None,
+            "",
+        );
+    }
+    transaction.commit();
+    refs
+}
+
+pub fn clone(source: String, directory: String, bare: bool, branch: String) {
+    // Creates a new repository in the directory (named after the source by
+    // default) with the source as its "origin" remote, and checks out the
+    // branch (the one the remote HEAD points to by default, or the same
+    // commit on a detached HEAD if the remote HEAD is detached). A bare clone
+    // has no working tree and keeps the remote branches as its own.
+    let url = fs::canonicalize(&source)
+        .ok()
+        .filter(|path| path.join(".rgit").is_dir())
+        .unwrap_or_else(|| panic!("{} is not a repository", source));
+    let url = url.to_str().unwrap().to_owned();
+    let directory = if !directory.is_empty() {
+        directory
+    } else {
+        Path::new(&url)
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_owned()
+    };
+    let is_empty = fs::read_dir(&directory).map_or(true, |mut entries| entries.next().is_none());
+    assert!(
+        is_empty,
+        "Destination {} already exists and is not empty",
+        directory
+    );
+
+    let remote_refs = get_remote_refs(url.clone(), "");
+    let (head_branch, head_oid) = get_remote_head(url.clone());
+    if !branch.is_empty() {
+        assert!(
+            remote_refs.contains_key(&format!("{}{}", REMOTE_REFS_BASE, branch)),
+            "Remote branch {} not found",
+            branch
+        );
+    }
+
+    println!("Cloning into {}", directory);
+    fs::create_dir_all(&directory).expect("Cannot create the destination");
+    env::set_current_dir(&directory).expect("Cannot enter the destination");
+    base::init().expect("Failed to create the repository");
+    add_remote("origin", &url);
+    let mut values = data::get_config();
+    if bare {
+        values.insert("core.bare".to_owned(), "true".to_owned());
+        values.insert(
+            "remote.origin.fetch".to_owned(),
+            format!("+{}*:{}*", REMOTE_REFS_BASE, REMOTE_REFS_BASE),
+        );
+    }
+    data::set_config(values);
+    fetch("origin".to_owned());
+
+    if remote_refs.values().all(|oid| oid.is_empty()) {
+        // Nothing to check out, HEAD stays on the branch the remote will have
+        let branch = if !branch.is_empty() {
+            branch
+        } else if !head_branch.is_empty() {
+            head_branch
+        } else {
+            "master".to_owned()
+        };
+        set_head_branch(&branch);
+        println!("You appear to have cloned an empty repository");
+        return;
+    }
+
+    let reason = format!("clone: from {}", url);
+    let branch = if !branch.is_empty() {
+        branch
+    } else if head_branch.is_empty() {
+        // Like the remote, we start on a detached HEAD
         data::update_ref(
-            format!("{}/{}", LOCAL_REFS_BASE, refname),
+            "HEAD".to_owned(),
             data::RefValue {
                 symbolic: false,
-                value: value.clone(),
+                value: head_oid.clone(),
             },
-            true,
-        )
+            false,
+            &reason,
+        );
+        if !bare {
+            base::read_tree(base::get_commit(head_oid).tree);
+        }
+        return;
+    } else {
+        head_branch
+    };
+
+    set_head_branch(&branch);
+    let oid = remote_refs
+        .get(&format!("{}{}", REMOTE_REFS_BASE, branch))
+        .cloned()
+        .unwrap_or("".to_owned());
+    if oid.is_empty() {
+        println!(
+            "warning: remote HEAD refers to {}, which does not exist, nothing checked out",
+            branch
+        );
+        return;
     }
+    if bare {
+        return;
+    }
+
+    // The branch is created through HEAD, so both logs say where it came from
+    data::update_ref(
+        "HEAD".to_owned(),
+        data::RefValue {
+            symbolic: false,
+            value: oid.clone(),
+        },
+        true,
+        &reason,
+    );
+    base::set_upstream(&branch, &format!("origin/{}", branch));
+    base::read_tree(base::get_commit(oid).tree);
 }
 
-pub fn push(remote_path: String, reference: String) {
+pub fn push(remote: String, reference: String) {
+    // A push to a configured remote also moves our remote-tracking ref
+    let url = get_url(&remote);
+    let remote_path = if !url.is_empty() { url } else { remote.clone() };
     let refs = get_remote_refs(remote_path.clone(), REMOTE_REFS_BASE);
     let empty = "".to_owned();
     let remote_ref = refs.get(&reference).unwrap_or(&empty);
     let local_ref = data::get_ref(reference.clone(), true).value;
-    assert!(local_ref != "".to_string());
+    assert!(!local_ref.is_empty());
 
     // Don't allow force push
-    assert!(
-        *remote_ref == "".to_owned() || base::is_ancestor_of(local_ref.clone(), remote_ref.clone())
-    );
+    assert!(remote_ref.is_empty() || base::is_ancestor_of(local_ref.clone(), remote_ref.clone()));
 
     let commit_oids = vec![&local_ref];
     base::copy_objects_in_commits_and_parents(commit_oids, remote_path.clone(), true);
 
+    // The remote ref could have moved since it was read, only update it if
+    // it is still where the checks above saw it
     data::set_rgit_dir(remote_path.as_str());
-    data::update_ref(
-        reference,
+    let mut transaction = data::RefTransaction::new();
+    transaction.update(
+        reference.clone(),
         data::RefValue {
             symbolic: false,
-            value: local_ref,
+            value: local_ref.clone(),
         },
         true,
+        Some(remote_ref.clone()),
+        "push",
     );
+    transaction.commit();
     data::reset_rgit_dir();
+
+    if !get_url(&remote).is_empty() {
+        let branch = reference.trim_start_matches(REMOTE_REFS_BASE);
+        data::update_ref(
+            format!("{}{}/{}", LOCAL_REFS_BASE, remote, branch),
+            data::RefValue {
+                symbolic: false,
+                value: local_ref,
+            },
+            false,
+            "update by push",
+        );
+    }
+}
+
+fn default_refspec(name: &str) -> String {
+    format!("+{}*:{}{}/*", REMOTE_REFS_BASE, LOCAL_REFS_BASE, name)
+}
+
+fn map_refspec(source: &str, destination: &str, name: &str) -> Option<String> {
+    // Local name of a remote ref, if the refspec covers it
+    match source.find("*") {
+        None if source == name => Some(destination.to_owned()),
+        None => None,
+        Some(star) => {
+            let (prefix, suffix) = (&source[..star], &source[star + 1..]);
+            if name.len() < prefix.len() + suffix.len()
+                || !name.starts_with(prefix)
+                || !name.ends_with(suffix)
+            {
+                return None;
+            }
+            let matched = &name[prefix.len()..name.len() - suffix.len()];
+            Some(destination.replacen("*", matched, 1))
+        }
+    }
+}
+
+fn get_remote_head(path: String) -> (String, String) {
+    // The branch the HEAD of another repository points to ("" if it is
+    // detached) and the commit it resolves to ("" if there is none yet)
+    data::set_rgit_dir(path.as_str());
+    let head = data::get_ref("HEAD".to_owned(), false);
+    let oid = data::get_ref("HEAD".to_owned(), true).value;
+    data::reset_rgit_dir();
+    let branch = if head.symbolic && head.value.starts_with(REMOTE_REFS_BASE) {
+        head.value.trim_start_matches(REMOTE_REFS_BASE).to_owned()
+    } else {
+        "".to_owned()
+    };
+    (branch, oid)
+}
+
+fn set_head_branch(branch: &str) {
+    data::update_ref(
+        "HEAD".to_owned(),
+        data::RefValue {
+            symbolic: true,
+            value: format!("{}{}", REMOTE_REFS_BASE, branch),
+        },
+        false,
+        "",
+    );
 }
 
 fn get_remote_refs(path: String, prefix: &str) -> HashMap<String, String> {
@@ -64,5 +442,5 @@ fn get_remote_refs(path: String, prefix: &str) -> HashMap<String, String> {
         refs.insert(refname, reference.value);
     }
     data::reset_rgit_dir();
-    return refs;
+    refs
 }
@@ -0,0 +1,247 @@
+// Applies (or reverts) a list of existing commits on top of HEAD, one at a time. When one
+// of them conflicts, the remaining work is saved in .rgit/sequencer/ so it can
+// be resumed with --continue or dropped with --abort.
+
+#[path = "data.rs"]
+#[allow(dead_code)]
+mod data;
+
+#[path = "base.rs"]
+#[allow(dead_code)]
+mod base;
+
+pub struct SequencerOptions {
+    // Append "(cherry picked from commit ...)" to the message
+    pub record_origin: bool,
+    // Only update the index and the working tree
+    pub no_commit: bool,
+    // Parent to compare against when the commit is a merge (1 based)
+    pub mainline: usize,
+}
+
+pub fn cherry_pick(names: Vec<String>, options: SequencerOptions) {
+    let todo = names
+        .into_iter()
+        .map(|name| ("pick".to_owned(), base::get_oid(name)))
+        .collect();
+    start("cherry-pick", todo, options);
+}
+
+pub fn revert(names: Vec<String>, options: SequencerOptions) {
+    let todo = names
+        .into_iter()
+        .map(|name| ("revert".to_owned(), base::get_oid(name)))
+        .collect();
+    start("revert", todo, options);
+}
+
+pub fn sequencer_continue() {
+    assert!(
+        !data::read_rgit_file("sequencer/head").is_empty(),
+        "There is no cherry-pick or revert in progress"
+    );
+    assert!(
+        data::get_index_conflicts().is_empty(),
+        "Fix the conflicts and run add on them before continuing"
+    );
+
+    let options = read_options();
+    if !options.no_commit && !get_current_action().0.is_empty() {
+        // The commit was not done by hand already
+        println!("{}", base::commit("", false));
+    }
+    clear_current_action();
+    run(read_todo(), options);
+}
+
+pub fn sequencer_abort() {
+    let orig_head = data::read_rgit_file("sequencer/head");
+    assert!(
+        !orig_head.is_empty(),
+        "There is no cherry-pick or revert in progress"
+    );
+
+    base::restore_tree(base::get_commit(orig_head.clone()).tree);
+    let operation = data::read_rgit_file("sequencer/operation");
+    data::update_ref(
+        "HEAD".to_owned(),
+        data::RefValue {
+            symbolic: false,
+            value: orig_head,
+        },
+        true,
+        &format!("{}: abort", operation),
+    );
+    clear_current_action();
+    data::remove_rgit_file("sequencer");
+}
+
+pub fn get_current_action() -> (String, String) {
+    // The ref of the commit being applied, along with its value
+    for name in ["CHERRY_PICK_HEAD", "REVERT_HEAD"].iter() {
+        let reference = data::get_ref(name.to_string(), false);
+        if !reference.value.is_empty() {
+            return (name.to_string(), reference.value);
+        }
+    }
+    ("".to_owned(), "".to_owned())
+}
+
+fn start(operation: &str, todo: Vec<(String, String)>, options: SequencerOptions) {
+    assert!(
+        data::read_rgit_file("sequencer/head").is_empty(),
+        "A cherry-pick or revert is already in progress, use --continue or --abort"
+    );
+    let head = data::get_ref("HEAD".to_owned(), true).value;
+    assert!(!head.is_empty(), "There are no commits yet");
+    for (_, oid) in todo.iter() {
+        // Fail early on merge commits without a mainline
+        get_parent(oid, options.mainline);
+    }
+
+    data::write_rgit_file("sequencer/head", &head);
+    data::write_rgit_file("sequencer/operation", operation);
+    write_options(&options);
+    run(todo, options);
+}
+
+fn run(todo: Vec<(String, String)>, options: SequencerOptions) {
+    for (i, (action, oid)) in todo.iter().enumerate() {
+        let (conflicts, message) = apply(action, oid, &options);
+
+        if !conflicts.is_empty() {
+            // Stop here and save what is left to do
+            let remaining: Vec<String> = todo[i + 1..]
+                .iter()
+                .map(|(action, oid)| format!("{} {}", action, oid))
+                .collect();
+            data::write_rgit_file("sequencer/todo", &remaining.join("\n"));
+            data::write_rgit_file("MERGE_MSG", &message);
+            let head_ref = if action == "pick" {
+                "CHERRY_PICK_HEAD"
+            } else {
+                "REVERT_HEAD"
+            };
+            data::update_ref(
+                head_ref.to_owned(),
+                data::RefValue {
+                    symbolic: false,
+                    value: oid.clone(),
+                },
+                false,
+                "",
+            );
+            for (_, conflict) in conflicts.iter() {
+                println!("{}", conflict);
+            }
+            println!("Could not {} {}", action, &oid[0..10]);
+            println!("Fix the conflicts, run add on them and then --continue (or --abort)");
+            return;
+        }
+
+        if options.no_commit {
+            data::write_rgit_file("MERGE_MSG", &message);
+        } else {
+            println!("{}", base::commit(&message, false));
+        }
+    }
+    data::remove_rgit_file("sequencer");
+}
+
+pub fn apply(
+    action: &str,
+    oid: &str,
+    options: &SequencerOptions,
+) -> (Vec<(String, String)>, String) {
+    let commit = base::get_commit(oid.to_owned());
+    let parent = get_parent(oid, options.mainline);
+    let parent_tree = if !parent.is_empty() {
+        base::get_commit(parent.clone()).tree
+    } else {
+        "".to_owned()
+    };
+
+    // Changes are applied on top of the index, so that several commits can be
+    // picked with --no-commit
+    let head_tree = base::write_tree_from(&data::get_index());
+
+    if action == "revert" {
+        // The inverse merge, going from the commit back to its parent
+        let subject = commit.message.lines().next().unwrap_or("");
+        let mut message = format!("Revert \"{}\"\n\nThis reverts commit {}", subject, oid);
+        if commit.parents.len() > 1 {
+            message += format!(", reversing\nchanges made to {}", parent).as_str();
+        }
+        message += ".";
+        let conflicts = base::read_tree_merged(commit.tree, head_tree, parent_tree, "");
+        return (conflicts, message);
+    }
+
+    let mut message = commit.message.trim_end().to_owned();
+    if options.record_origin {
+        message = format!("{}\n\n(cherry picked from commit {})", message, oid);
+    }
+    let conflicts = base::read_tree_merged(parent_tree, head_tree, commit.tree, "");
+    (conflicts, message)
+}
+
+fn get_parent(oid: &str, mainline: usize) -> String {
+    let commit = base::get_commit(oid.to_owned());
+    if commit.parents.len() > 1 {
+        assert!(
+            mainline > 0 && mainline <= commit.parents.len(),
+            "Commit {} is a merge, a valid -m <parent> is required",
+            oid
+        );
+        return commit.parents[mainline - 1].clone();
+    }
+    assert!(
+        mainline == 0,
+        "Commit {} is not a merge, -m cannot be used with it",
+        oid
+    );
+    commit.parents[0].clone()
+}
+
+fn read_todo() -> Vec<(String, String)> {
+    let mut todo = vec![];
+    for line in data::read_rgit_file("sequencer/todo").lines() {
+        let items: Vec<&str> = line.splitn(2, " ").collect();
+        todo.push((items[0].to_owned(), items[1].to_owned()));
+    }
+    todo
+}
+
+fn write_options(options: &SequencerOptions) {
+    let content = format!(
+        "record-origin {}\nno-commit {}\nmainline {}\n",
+        options.record_origin, options.no_commit, options.mainline
+    );
+    data::write_rgit_file("sequencer/opts", &content);
+}
+
+fn read_options() -> SequencerOptions {
+    let mut options = SequencerOptions {
+        record_origin: false,
+        no_commit: false,
+        mainline: 0,
+    };
+    for line in data::read_rgit_file("sequencer/opts").lines() {
+        let items: Vec<&str> = line.splitn(2, " ").collect();
+        match items[0] {
+            "record-origin" => options.record_origin = items[1] == "true",
+            "no-commit" => options.no_commit = items[1] == "true",
+            "mainline" => options.mainline = items[1].parse().unwrap(),
+            _ => (),
+        }
+    }
+    options
+}
+
+fn clear_current_action() {
+    let current = get_current_action();
+    if !current.0.is_empty() {
+        data::delete_ref(current.0, false);
+    }
+    data::remove_rgit_file("MERGE_MSG");
+}
@@ -0,0 +1,253 @@
+// Ed25519 signatures for commits and tags, without any external tool. The
+// private key is kept in .rgit/signing-key (or the file set in the
+// user.signingkey config) and signed objects get a "signature <key> <sig>"
+// header, computed over the object without that line. Keys listed in
+// .rgit/allowed-signers, along with our own, are the ones we trust.
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+
+#[path = "data.rs"]
+#[allow(dead_code)]
+mod data;
+
+static SIGNATURE_HEADER: &str = "signature ";
+
+pub fn generate_key() -> String {
+    // Creates a new key pair and returns the public key
+    let path = get_key_path();
+    assert!(
+        fs::metadata(&path).is_err(),
+        "A signing key already exists at {}",
+        path
+    );
+
+    let mut secret = [0u8; 32];
+    fs::File::open("/dev/urandom")
+        .and_then(|mut random| random.read_exact(&mut secret))
+        .expect("Cannot generate a random key");
+    // Only the owner may ever read the secret key, even while it is written
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .and_then(|mut file| file.write_all(format!("{}\n", to_hex(&secret)).as_bytes()))
+        .expect("Failed to write the signing key");
+    get_public_key()
+}
+
+pub fn get_public_key() -> String {
+    to_hex(&read_key().verifying_key().to_bytes())
+}
+
+pub fn trust_key(name: &str, key: &str) {
+    assert!(
+        parse_public_key(key).is_some(),
+        "{} is not a valid public key",
+        key
+    );
+    let mut signers = data::read_rgit_file("allowed-signers");
+    signers += format!("{} {}\n", key, name).as_str();
+    data::write_rgit_file("allowed-signers", &signers);
+}
+
+pub fn add_signature(object: &str) -> String {
+    // Adds the signature header after the existing ones
+    let key = read_key();
+    let signature = key.sign(object.as_bytes());
+    let header = format!(
+        "{}{} {}\n",
+        SIGNATURE_HEADER,
+        to_hex(&key.verifying_key().to_bytes()),
+        to_hex(&signature.to_bytes())
+    );
+    let end = object.find("\n\n").map(|i| i + 1).unwrap_or(object.len());
+    format!("{}{}{}", &object[..end], header, &object[end..])
+}
+
+pub fn verify(oid: &str, object_type: &str) -> (bool, String) {
+    // Whether the object carries a good signature from a trusted key, along
+    // with a description of what was found
+    let content = data::get_object(oid.to_owned(), object_type.to_owned());
+    let headers_end = content.find("\n\n").unwrap_or(content.len());
+    let header_line = content[..headers_end]
+        .lines()
+        .find(|line| line.starts_with(SIGNATURE_HEADER));
+    let header_line = match header_line {
+        Some(line) => line,
+        None => return (false, format!("No signature on {} {}", object_type, oid)),
+    };
+
+    // The payload is the object as it was before being signed
+    let payload = content.replacen(&format!("{}\n", header_line), "", 1);
+    let items: Vec<&str> = header_line[SIGNATURE_HEADER.len()..].split(" ").collect();
+    let key = parse_public_key(items[0]);
+    let signature = from_hex(items.get(1).unwrap_or(&""));
+    if key.is_none() || signature.len() != 64 {
+        return (
+            false,
+            format!("Malformed signature on {} {}", object_type, oid),
+        );
+    }
+    let mut signature_bytes = [0u8; 64];
+    signature_bytes.copy_from_slice(&signature);
+    let signature = Signature::from_bytes(&signature_bytes);
+    if key.unwrap().verify(payload.as_bytes(), &signature).is_err() {
+        return (false, format!("BAD signature from key {}", items[0]));
+    }
+
+    let signer = get_signer_name(items[0]);
+    if signer.is_empty() {
+        return (
+            false,
+            format!("Good signature from an untrusted key {}", items[0]),
+        );
+    }
+    (
+        true,
+        format!("Good signature from {} (key {})", signer, items[0]),
+    )
+}
+
+fn get_signer_name(key: &str) -> String {
+    let own_key = read_key_file();
+    if !own_key.is_empty() && get_public_key() == key {
+        return data::get_identity();
+    }
+    for line in data::read_rgit_file("allowed-signers").lines() {
+        let items: Vec<&str> = line.splitn(2, " ").collect();
+        if items.len() == 2 && items[0] == key {
+            return items[1].to_owned();
+        }
+    }
+    "".to_owned()
+}
+
+fn get_key_path() -> String {
+    let path = data::get_config_value("user.signingkey");
+    if !path.is_empty() {
+        return path;
+    }
+    data::get_rgit_file_path("signing-key")
+}
+
+fn read_key_file() -> String {
+    fs::read_to_string(get_key_path())
+        .unwrap_or("".to_owned())
+        .trim()
+        .to_owned()
+}
+
+fn read_key() -> SigningKey {
+    let secret = from_hex(&read_key_file());
+    assert!(
+        secret.len() == 32,
+        "No signing key found, create one with signing-key --generate"
+    );
+    let mut secret_bytes = [0u8; 32];
+    secret_bytes.copy_from_slice(&secret);
+    SigningKey::from_bytes(&secret_bytes)
+}
+
+fn parse_public_key(key: &str) -> Option<VerifyingKey> {
+    let bytes = from_hex(key);
+    if bytes.len() != 32 {
+        return None;
+    }
+    let mut key_bytes = [0u8; 32];
+    key_bytes.copy_from_slice(&bytes);
+    VerifyingKey::from_bytes(&key_bytes).ok()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Vec<u8> {
+    // An empty result means the text was not valid hex
+    if !text.len().is_multiple_of(2) || !text.chars().all(|c| c.is_ascii_hexdigit()) {
+        return vec![];
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::with_repo;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn store(object: &str) -> String {
+        data::hash_object(object.as_bytes(), "commit".to_owned())
+    }
+
+    fn signed_commit(message: &str) -> String {
+        let commit = format!("tree {}\n\n{}\n", "0".repeat(40), message);
+        add_signature(&commit)
+    }
+
+    #[test]
+    fn signed_commit_verifies() {
+        with_repo(|| {
+            let key = generate_key();
+            let object = signed_commit("signed");
+            assert!(object.contains(&format!("\nsignature {} ", key)));
+            assert!(object.ends_with("\n\nsigned\n"));
+
+            let (good, description) = verify(&store(&object), "commit");
+            assert!(good, "{}", description);
+            assert!(description.starts_with("Good signature from "));
+        });
+    }
+
+    #[test]
+    fn secret_key_is_private() {
+        with_repo(|| {
+            generate_key();
+            let mode = fs::metadata(get_key_path()).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        });
+    }
+
+    #[test]
+    fn tampered_commit_fails() {
+        with_repo(|| {
+            generate_key();
+            let object = signed_commit("signed").replace("signed\n", "changed\n");
+            let (good, description) = verify(&store(&object), "commit");
+            assert!(!good);
+            assert!(description.starts_with("BAD signature"));
+
+            let unsigned = format!("tree {}\n\nunsigned\n", "0".repeat(40));
+            let (good, description) = verify(&store(&unsigned), "commit");
+            assert!(!good);
+            assert!(description.starts_with("No signature"));
+        });
+    }
+
+    #[test]
+    fn only_trusted_keys_are_good() {
+        with_repo(|| {
+            let old_key = generate_key();
+            let oid = store(&signed_commit("signed"));
+
+            // Signed with a key that is not ours anymore
+            fs::remove_file(get_key_path()).unwrap();
+            generate_key();
+            let (good, description) = verify(&oid, "commit");
+            assert!(!good);
+            assert!(description.starts_with("Good signature from an untrusted key"));
+
+            trust_key("Old Key", &old_key);
+            let (good, description) = verify(&oid, "commit");
+            assert!(good);
+            assert!(description.starts_with("Good signature from Old Key"));
+        });
+    }
+}
@@ -0,0 +1,240 @@
+// Saves the local changes away as commits, so the working tree can be cleaned
+// and the changes brought back later. Each stash is a commit whose tree is the
+// working tree, with HEAD, a commit of the index and (optionally) a commit of
+// the untracked files as its parents. refs/stash points to the latest one and
+// its reflog keeps the whole stack.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+#[path = "data.rs"]
+#[allow(dead_code)]
+mod data;
+
+#[path = "base.rs"]
+#[allow(dead_code)]
+mod base;
+
+static STASH_REF: &str = "refs/stash";
+
+pub struct StashOptions {
+    pub message: String,
+    pub include_untracked: bool,
+    pub keep_index: bool,
+}
+
+pub fn push(options: &StashOptions) {
+    assert!(
+        data::get_index_conflicts().is_empty(),
+        "Cannot stash while there are unmerged paths"
+    );
+    let head = data::get_ref("HEAD".to_owned(), true).value;
+    assert!(!head.is_empty(), "There are no commits yet");
+
+    let head_files = base::get_tree(base::get_commit(head.clone()).tree, "".to_owned());
+    let index = data::get_index();
+    let working_tree = base::get_working_tree();
+    let mut tracked = working_tree.clone();
+    tracked.retain(|path, _| index.contains_key(path));
+    let mut untracked = working_tree.clone();
+    untracked.retain(|path, _| !index.contains_key(path));
+    if !options.include_untracked {
+        untracked.clear();
+    }
+    if index == head_files && tracked == index && untracked.is_empty() {
+        println!("No local changes to save");
+        return;
+    }
+
+    let branch = base::get_branch_name();
+    let branch = if !branch.is_empty() {
+        branch
+    } else {
+        "(no branch)".to_owned()
+    };
+    let subject = base::get_commit(head.clone()).message;
+    let subject = subject.lines().next().unwrap_or("");
+    let description = format!("{}: {} {}", branch, &head[0..7], subject);
+
+    let index_commit = write_commit(
+        &index,
+        &vec![head.clone()],
+        &format!("index on {}", description),
+    );
+    let mut parents = vec![head.clone(), index_commit];
+    if !untracked.is_empty() {
+        parents.push(write_commit(
+            &untracked,
+            &vec![],
+            &format!("untracked files on {}", description),
+        ));
+    }
+    let message = if !options.message.is_empty() {
+        format!("On {}: {}", branch, options.message)
+    } else {
+        format!("WIP on {}", description)
+    };
+    let stash = write_commit(&tracked, &parents, &message);
+
+    data::update_ref(
+        STASH_REF.to_owned(),
+        data::RefValue {
+            symbolic: false,
+            value: stash,
+        },
+        false,
+        &message,
+    );
+
+    // Put the working tree back to HEAD (or to the index, with --keep-index)
+    let mut old_files = index.clone();
+    old_files.extend(untracked);
+    let new_files = if options.keep_index {
+        index
+    } else {
+        head_files
+    };
+    base::update_working_files(&old_files, &new_files);
+    data::set_index(new_files);
+    println!("Saved working directory and index state {}", message);
+}
+
+pub fn apply(name: String) -> bool {
+    // Returns false when applying the stash resulted in conflicts
+    let (_, stash) = get_stash(&name);
+    assert!(
+        data::get_index_conflicts().is_empty(),
+        "Cannot apply a stash while there are unmerged paths"
+    );
+    let index = data::get_index();
+    let mut tracked = base::get_working_tree();
+    tracked.retain(|path, _| index.contains_key(path));
+    assert!(
+        tracked == index,
+        "Cannot apply a stash on top of unstaged changes, add them first"
+    );
+
+    let commit = base::get_commit(stash.clone());
+    let untracked = if commit.parents.len() > 2 {
+        let untracked_tree = base::get_commit(commit.parents[2].clone()).tree;
+        base::get_tree(untracked_tree, "".to_owned())
+    } else {
+        HashMap::new()
+    };
+    for path in untracked.keys() {
+        assert!(
+            !Path::new(path).exists(),
+            "{} already exists, cannot restore it from the stash",
+            path
+        );
+    }
+
+    let base_tree = base::get_commit(commit.parents[0].clone()).tree;
+    let base_files = base::get_tree(base_tree.clone(), "".to_owned());
+    let conflicts =
+        base::read_tree_merged(base_tree, base::write_tree_from(&index), commit.tree, "");
+    base::update_working_files(&HashMap::new(), &untracked);
+    if !conflicts.is_empty() {
+        for (_, conflict) in conflicts.iter() {
+            println!("{}", conflict);
+        }
+        return false;
+    }
+
+    // The changes are left unstaged, except for the new files that have to
+    // stay in the index to be tracked
+    let mut new_index = index.clone();
+    for (path, oid) in data::get_index() {
+        if !index.contains_key(&path) && !base_files.contains_key(&path) {
+            new_index.insert(path, oid);
+        }
+    }
+    data::set_index(new_index);
+    true
+}
+
+pub fn pop(name: String) {
+    if apply(name.clone()) {
+        drop(name);
+    } else {
+        println!("The stash entry is kept in case you need it again.");
+    }
+}
+
+pub fn drop(name: String) {
+    let (position, stash) = get_stash(&name);
+    let mut stack = read_stack();
+    stack.remove(position);
+    if stack.is_empty() {
+        data::delete_ref(STASH_REF.to_owned(), false);
+    } else {
+        data::update_ref(
+            STASH_REF.to_owned(),
+            data::RefValue {
+                symbolic: false,
+                value: stack[0].new.clone(),
+            },
+            false,
+            "",
+        );
+        // Put back the log without the dropped entry
+        stack.reverse();
+        data::write_reflog(STASH_REF, &stack);
+    }
+    println!("Dropped stash@{{{}}} ({})", position, stash);
+}
+
+pub fn clear() {
+    if !data::get_ref(STASH_REF.to_owned(), false).value.is_empty() {
+        data::delete_ref(STASH_REF.to_owned(), false);
+    }
+}
+
+pub fn list() -> Vec<(String, String)> {
+    // Name and message of every stash, the latest first
+    read_stack()
+        .into_iter()
+        .enumerate()
+        .map(|(i, entry)| (format!("stash@{{{}}}", i), entry.message))
+        .collect()
+}
+
+pub fn get_stash(name: &str) -> (usize, String) {
+    // Position in the stack and oid of "stash@{n}" (or just "n"), defaults
+    // to the latest stash
+    let position = name
+        .trim_start_matches("stash@{")
+        .trim_end_matches("}")
+        .to_owned();
+    let position: usize = if position.is_empty() || position == "stash" {
+        0
+    } else {
+        position
+            .parse()
+            .unwrap_or_else(|_| panic!("{} is not a valid stash reference", name))
+    };
+    let stack = read_stack();
+    assert!(!stack.is_empty(), "No stash entries found");
+    assert!(
+        position < stack.len(),
+        "stash@{{{}}} does not exist",
+        position
+    );
+    (position, stack[position].new.clone())
+}
+
+fn write_commit(files: &HashMap<String, String>, parents: &Vec<String>, message: &str) -> String {
+    let mut commit = format!("tree {}\n", base::write_tree_from(files));
+    for parent in parents {
+        commit += format!("parent {}\n", parent).as_str();
+    }
+    commit += format!("\n{}\n", message).as_str();
+    data::hash_object(&commit.into_bytes(), "commit".to_owned())
+}
+
+fn read_stack() -> Vec<data::ReflogEntry> {
+    // The reflog of refs/stash, with the latest stash first
+    let mut stack = data::read_reflog(STASH_REF);
+    stack.reverse();
+    stack
+}
@@ -0,0 +1,19 @@
+// Helpers shared by the tests of every module. Each copy of a module reads
+// the repository from the current directory (.rgit), so the tests change
+// directory and have to run one at a time.
+
+use std::env;
+use std::fs;
+use std::sync::Mutex;
+
+static REPO_LOCK: Mutex<()> = Mutex::new(());
+
+pub fn with_repo(test: impl FnOnce()) {
+    // Runs the test inside a new, empty repository
+    let _guard = REPO_LOCK.lock().unwrap_or_else(|error| error.into_inner());
+    let dir = tempfile::tempdir().unwrap();
+    env::set_current_dir(dir.path()).unwrap();
+    fs::create_dir_all(".rgit/objects").unwrap();
+    fs::write(".rgit/HEAD", "ref: refs/heads/master").unwrap();
+    test();
+}